@@ -7,17 +7,180 @@ extern mod extra;
 extern mod zmq;
 extern mod tnetstring;
 
+use std::ascii::StrAsciiExt;
 use std::hashmap::HashMap;
-use std::{cast, io, str, uint};
+use std::{cast, comm, f64, int, io, libc, local_data, str, task, uint, util, vec};
+use std::comm::{Chan, Port};
+use extra::crypto::digest::Digest;
+use extra::crypto::sha1::Sha1;
+use extra::crypto::sha2::Sha256;
+use extra::flate;
 use extra::json;
+use extra::sort;
+use extra::time;
 use extra::json::ToStr;
 
 pub struct Connection {
+    ctx: zmq::Context,
     sender_id: Option<~str>,
     req_addrs: @~[~str],
     rep_addrs: @~[~str],
     req: zmq::Socket,
     rep: zmq::Socket,
+    buffer_pool: Option<@mut BufferPool>,
+    path_limits: @mut ~[PathLimit],
+    ordered_replies: Option<@mut OrderedReplies>,
+    stash: @mut ReplyStash,
+    header_case: HeaderCase,
+    max_body_size: Option<uint>,
+    prefetch: Option<@Prefetch>,
+    compress_threshold: uint,
+    hooks: @mut RequestHooks,
+    sorted_headers: bool,
+}
+
+// The handler's end of a prefetch task. The task may only run as many
+// messages ahead as it holds credits for, and the handler hands a credit
+// back for every message it takes.
+struct Prefetch {
+    requests: Port<Result<Request, ReadError>>,
+    credits: Chan<()>,
+    control: zmq::Socket,
+    stopped: Port<()>,
+}
+
+// Why reading a request failed. A malformed message carries the sender
+// and connection id it came from, when those could still be read.
+enum ReadError {
+    Malformed(~str, Option<(~str, ~str)>),
+    SocketError(~str),
+}
+
+impl ReadError {
+    fn message(self) -> ~str {
+        match self {
+            Malformed(e, _) => e,
+            SocketError(e) => e,
+        }
+    }
+}
+
+pub static MAX_IDS_PER_MESSAGE: uint = 128u;
+
+// Callbacks for tracing, run when a request is received and when its reply
+// goes out.
+struct RequestHooks {
+    start: Option<@fn(&Request)>,
+    end: Option<@fn(&Request)>,
+}
+
+// Remembers who to answer for requests handed off to other tasks, keyed by
+// an opaque token the worker can carry instead of the request.
+struct ReplyStash {
+    next_token: uint,
+    targets: HashMap<~str, (~str, ~str)>,
+}
+
+// Allows `rate` requests per second to paths under `prefix`.
+struct PathLimit {
+    prefix: ~str,
+    rate: uint,
+    window_start: u64,
+    count: uint,
+}
+
+// Recycles request body buffers. Bodies are copied out of the zmq message
+// anyway, so a recycled request saves that allocation on the next one.
+struct BufferPool {
+    buffers: ~[~[u8]],
+    size: uint,
+    reuses: uint,
+}
+
+impl BufferPool {
+    fn take(&mut self) -> ~[u8] {
+        if self.buffers.is_empty() {
+            ~[]
+        } else {
+            self.reuses += 1u;
+            self.buffers.pop()
+        }
+    }
+
+    fn give(&mut self, buf: ~[u8]) {
+        if self.buffers.len() < self.size {
+            let mut buf = buf;
+            buf.truncate(0u);
+            self.buffers.push(buf);
+        }
+    }
+}
+
+// Holds back replies to pipelined requests until every earlier request on
+// the same connection id has been answered. A request may be answered in
+// several parts; its slot stays at the head until the final part is in.
+struct OrderedReplies {
+    next_sequence: uint,
+    pending: HashMap<~str, ~[PendingReply]>,
+}
+
+struct PendingReply {
+    sequence: uint,
+    parts: ~[~[u8]],
+    finished: bool,
+}
+
+impl OrderedReplies {
+    fn track(&mut self, req: &mut Request) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1u;
+        req.sequence = Some(sequence);
+
+        let slot = PendingReply { sequence: sequence, parts: ~[], finished: false };
+        self.pending.mangle(req.id.clone(), slot,
+            |_, slot| ~[slot],
+            |_, slots, slot| slots.push(slot));
+    }
+
+    // Records part of the reply to a tracked request, returning whichever
+    // parts can now go out in arrival order.
+    fn complete(&mut self, id: &str, sequence: uint, body: &[u8],
+                finished: bool) -> ~[~[u8]] {
+        let key = id.to_owned();
+        let mut ready = ~[];
+
+        let drained = match self.pending.find_mut(&key) {
+            None => return ~[body.to_owned()],
+            Some(slots) => {
+                match slots.iter().position(|slot| slot.sequence == sequence) {
+                    None => return ~[body.to_owned()],
+                    Some(i) => {
+                        slots[i].parts.push(body.to_owned());
+                        slots[i].finished = finished;
+                    }
+                }
+
+                while !slots.is_empty() {
+                    let parts = util::replace(&mut slots[0u].parts, ~[]);
+                    ready.push_all_move(parts);
+
+                    if !slots[0u].finished { break; }
+                    slots.shift();
+                }
+
+                slots.is_empty()
+            }
+        };
+
+        if drained { self.pending.remove(&key); }
+
+        ready
+    }
+
+    // Drops the slots of a connection id that has gone away.
+    fn forget(&mut self, id: &str) {
+        self.pending.remove(&id.to_owned());
+    }
 }
 
 pub fn connect(
@@ -61,11 +224,22 @@ pub fn connect(
     }
 
     Connection {
+        ctx: ctx,
         sender_id: sender_id,
         req_addrs: @req_addrs,
         rep_addrs: @rep_addrs,
         req: req,
-        rep: rep
+        rep: rep,
+        buffer_pool: None,
+        path_limits: @mut ~[],
+        ordered_replies: None,
+        stash: @mut ReplyStash { next_token: 0u, targets: HashMap::new() },
+        header_case: PreserveCase,
+        max_body_size: None,
+        prefetch: None,
+        compress_threshold: 256u,
+        hooks: @mut RequestHooks { start: None, end: None },
+        sorted_headers: false
     }
 }
 
@@ -73,330 +247,6470 @@ impl Connection {
     fn req_addrs(&self) -> @~[~str] { self.req_addrs }
     fn rep_addrs(&self) -> @~[~str] { self.rep_addrs }
 
-    pub fn recv(&self) -> Result<Request, ~str> {
-        match unsafe { self.req.recv(0) } {
-            Err(e) => Err(e.to_str()),
-            Ok(msg) => msg.with_bytes(|bytes| parse(bytes)),
+    // The zmq transport requests arrive over, such as "tcp" or "ipc", taken
+    // from the scheme of the request addresses.
+    pub fn transport(&self) -> ~str {
+        let mut schemes = do self.req_addrs.map |addr| {
+            match addr.find_str("://") {
+                Some(i) => addr.slice_to(i).to_owned(),
+                None => ~"",
+            }
+        };
+        schemes.dedup();
+
+        match schemes.len() {
+            0u => ~"",
+            1u => schemes.pop(),
+            _ => ~"mixed",
         }
     }
 
-    pub fn send(&self,
-            uuid: &str,
-            id: &[~str],
-            body: &[u8]) -> Result<(), ~str> {
-        let id = str_as_bytes(id.connect(" "));
-
-        let mut msg = ~[];
-
-        msg.push_all(uuid.as_bytes());
-        msg.push(' ' as u8);
-        msg.push_all(tnetstring::to_bytes(&tnetstring::Str(id)));
-        msg.push(' ' as u8);
-        msg.push_all(body);
-
-        match self.rep.send(msg, 0) {
-          Err(e) => Err(e.to_str()),
-          Ok(()) => Ok(()),
+    pub fn recv(&self) -> Result<Request, ~str> {
+        loop {
+            match self.read_request(true) {
+                Ok(Some(request)) => return Ok(request),
+                Ok(None) => { },
+                Err(e) => return Err(e),
+            }
         }
     }
 
-    pub fn reply(&self, req: &Request, body: &[u8]) -> Result<(), ~str> {
-        //self.send(req.uuid, [copy req.id], body)
-        self.send(req.uuid, [req.id.clone()], body)
+    // Returns a request if one is already waiting, without blocking.
+    pub fn recv_nonblock(&self) -> Result<Option<Request>, ~str> {
+        self.read_request(false)
     }
 
-    pub fn reply_http(&self,
-                  req: &Request,
-                  code: uint,
-                  status: &str,
-                  headers: Headers,
-                  body: ~str) -> Result<(), ~str> {
-        let mut rep = ~[];
-
-        rep.push_all(str_as_bytes(fmt!("HTTP/1.1 %u ", code)));
-        rep.push_all(status.as_bytes());
-        rep.push_all("\r\n".as_bytes());
-        rep.push_all("Content-Length: ".as_bytes());
-        rep.push_all(str_as_bytes(uint::to_str(body.len())));
-        rep.push_all("\r\n".as_bytes());
-
-        for (key, values) in headers.iter() {
-            for value in values.iter() {
-                rep.push_all(str_as_bytes(*key + ": " + *value + "\r\n"));
+    // Waits up to `timeout_ms` for a request, or forever if it's negative.
+    // The prefetch task owns the request socket, so this can't be used once
+    // prefetching is on.
+    pub fn recv_timeout(&self, timeout_ms: int) -> Result<Option<Request>, ~str> {
+        if self.prefetch.is_some() {
+            return Err(~"recv_timeout is not available while prefetching");
+        }
+
+        if timeout_ms < 0 {
+            return match self.recv() {
+                Ok(request) => Ok(Some(request)),
+                Err(e) => Err(e),
             };
         }
-        rep.push_all("\r\n".as_bytes());
-        rep.push_all(str_as_bytes(body));
 
-        self.reply(req, rep)
-    }
+        let deadline = time::precise_time_ns() + timeout_ms as u64 * 1000000u64;
 
-    pub fn term (&mut self) {
-        self.req.close();
-        self.rep.close();
+        loop {
+            match self.read_request(false) {
+                Ok(None) => { },
+                result => return result,
+            }
+
+            let now = time::precise_time_ns();
+            if now >= deadline { return Ok(None); }
+
+            let mut items = [self.req.as_poll_item(zmq::POLLIN)];
+            let remaining_ms = (deadline - now + 999999u64) / 1000000u64;
+            match zmq::poll(items, remaining_ms as i64) {
+                Ok(()) => { },
+                Err(e) => return Err(fmt!("poll failed: %s", e.to_str())),
+            }
+        }
     }
-}
 
-// TODO: there is no `as_bytes' for ~str that will return ~[u8].
-fn str_as_bytes(s: ~str) -> ~[u8] {
-    let s = s.clone();
-    let mut buf: ~[u8] = unsafe { cast::transmute(s) };
-    buf.pop();
-    buf
-}
+    // Reads messages until one has to be handed to the handler. Without
+    // `wait`, gives up with `None` as soon as nothing is waiting.
+    fn read_request(&self, wait: bool) -> Result<Option<Request>, ~str> {
+        match self.read_message(wait) {
+            Ok(request) => Ok(request),
+            Err(e) => Err(e.message()),
+        }
+    }
 
-pub type Headers = HashMap<~str, ~[~str]>;
+    fn read_message(&self, wait: bool) -> Result<Option<Request>, ReadError> {
+        loop {
+            let request = match self.prefetch {
+                Some(prefetch) => {
+                    if !wait && !prefetch.requests.peek() { return Ok(None); }
 
-pub fn Headers() -> Headers {
-    HashMap::new()
-}
+                    match prefetch.requests.try_recv() {
+                        None => return Err(SocketError(~"prefetch task has stopped")),
+                        Some(request) => {
+                            prefetch.credits.try_send(());
+                            match request {
+                                Ok(request) => self.admit_message(request),
+                                Err(Malformed(e, target)) => Err(self.reject_malformed(e, target)),
+                                Err(e) => Err(e),
+                            }
+                        }
+                    }
+                }
+                None => {
+                    let flags = if wait { 0 } else { zmq::DONTWAIT };
 
-#[deriving(Clone)]
-pub struct Request {
-    uuid: ~str,
-    id: ~str,
-    path: ~str,
-    headers: Headers,
-    body: ~[u8],
-    json_body: Option<~json::Object>,
-}
+                    match unsafe { self.req.recv(flags) } {
+                        Err(zmq::EAGAIN) if !wait => return Ok(None),
+                        Err(e) => return Err(SocketError(fmt!("recv failed: %s", e.to_str()))),
+                        Ok(msg) => msg.with_bytes(|bytes| self.accept_message(bytes)),
+                    }
+                }
+            };
 
-impl Request {
-    pub fn is_disconnect(&self) -> bool {
-        do self.json_body.map_default(false) |map| {
-            match map.find(&~"type") {
-              Some(&json::String(ref typ)) => *typ == ~"disconnect",
-              _ => false,
+            match request {
+                Ok(Some(request)) => return Ok(Some(request)),
+                Ok(None) => { },
+                Err(e) => return Err(e),
             }
         }
     }
 
-    pub fn should_close(&self) -> bool {
-        match self.headers.find(&~"connection") {
-          None => { },
-          Some(conn) => {
-            if conn.len() == 1u && conn[0u] == ~"close" { return true; }
-          }
+    // Parses a raw message, returning `None` when the request was already
+    // answered on the handler's behalf.
+    fn accept(&self, bytes: &[u8]) -> Result<Option<Request>, ~str> {
+        match self.accept_message(bytes) {
+            Ok(request) => Ok(request),
+            Err(e) => Err(e.message()),
         }
+    }
 
-        match self.headers.find(&~"VERSION") {
-          None => false,
-          Some(version) => {
-            version.len() == 1u && version[0u] == ~"HTTP/1.0"
-          }
+    fn accept_message(&self, bytes: &[u8]) -> Result<Option<Request>, ReadError> {
+        match self.parse_message(bytes) {
+            Ok(request) => self.admit_message(request),
+            Err(e) => Err(self.reject_malformed(e, message_target(bytes))),
         }
     }
-}
-
-fn parse(bytes: &[u8]) -> Result<Request, ~str> {
-    io::with_bytes_reader(bytes, parse_reader)
-}
 
-fn parse_reader(rdr: @io::Reader) -> Result<Request, ~str> {
-    let uuid = match parse_uuid(rdr) {
-        Ok(uuid) => uuid,
-        Err(e) => return Err(e),
-    };
+    fn admit_message(&self, request: Request) -> Result<Option<Request>, ReadError> {
+        match self.admit(request) {
+            Ok(request) => Ok(request),
+            Err(e) => Err(SocketError(e)),
+        }
+    }
 
-    let id = match parse_id(rdr) {
-        Ok(value) => value,
-        Err(e) => return Err(e),
-    };
+    // A message that fails to parse never reaches the handler, so its
+    // client is sent the 400 here, provided it can be told who to send it
+    // to. Otherwise the client would wait for a reply that never comes.
+    fn reject_malformed(&self, error: ~str, target: Option<(~str, ~str)>) -> ReadError {
+        let (uuid, id) = match target {
+            Some(target) => target,
+            None => return Malformed(error, None),
+        };
 
-    let path = match parse_path(rdr) {
-        Ok(value) => value,
-        Err(e) => return Err(e),
-    };
+        let mut headers = Headers();
+        set_header(&mut headers, "Connection", ~"close");
+        let head = http_head("HTTP/1.1", 400u, status_reason(400u), &headers, Some(0u),
+                             self.header_case, self.sorted_headers);
 
-    let headers = match parse_headers(rdr) {
-        Ok(headers) => headers,
-        Err(e) => return Err(e),
-    };
+        match self.send(uuid, [id.clone()], head) {
+            Ok(()) => Malformed(error, Some((uuid, id))),
+            Err(e) => SocketError(e),
+        }
+    }
 
-    let body = match parse_body(rdr) {
-        Ok(body) => body,
-        Err(e) => return Err(e),
-    };
+    fn admit(&self, request: Request) -> Result<Option<Request>, ~str> {
+        let mut request = request;
 
-    // Extract out the json body if we have it.
-    let json_body = match headers.find(&~"METHOD") {
-      None => None,
-      Some(method) => {
-        if method.len() == 1u && method[0u] == ~"JSON" {
-            match json::from_str(str::from_bytes(body)) {
-              Ok(json::Object(map)) => Some(map),
-              Ok(_) => return Err(~"json body is not a dictionary"),
-              Err(e) =>
-                return Err(fmt!("invalid JSON string: %s", e.to_str())),
-            }
-        } else { None }
-      }
-    };
+        match self.hooks.start {
+            Some(f) => f(&request),
+            None => { },
+        }
 
-    Ok(Request {
-        uuid: uuid,
-        id: id,
-        path: path,
-        headers: headers,
-        body: body,
-        json_body: json_body
-    })
-}
+        match self.ordered_replies {
+            Some(ordered) if request.json_body.is_none() =>
+                ordered.track(&mut request),
+            Some(ordered) if request.is_disconnect() =>
+                ordered.forget(request.id),
+            _ => { },
+        }
 
-fn read_str(rdr: @io::Reader) -> Option<~str> {
-    let mut s = ~"";
+        // Mongrel2's own JSON messages are never throttled. The 429 goes
+        // through reply() so it waits its turn behind earlier requests.
+        if request.json_body.is_none() &&
+           self.throttle_at(request.path, time::precise_time_ns()) {
+            let mut headers = Headers();
+            with_retry_after(&mut headers, 1u);
 
-    while !rdr.eof() {
-        let ch = rdr.read_char();
-        if ch == ' ' {
-            return Some(s);
-        } else {
-            s.push_char(ch);
+            match self.reply_status(&request, 429u, headers) {
+                Ok(()) => return Ok(None),
+                Err(e) => return Err(e),
+            }
         }
+
+        Ok(Some(request))
     }
 
-    None
-}
+    pub fn set_path_limit(&mut self, prefix: &str, rate: uint) {
+        for limit in self.path_limits.mut_iter() {
+            if limit.prefix.as_slice() == prefix {
+                limit.rate = rate;
+                return;
+            }
+        }
 
-fn parse_uuid(rdr: @io::Reader) -> Result<~str, ~str> {
-    match read_str(rdr) {
-        Some(s) => Ok(s),
-        None => Err(~"invalid sender uuid"),
+        self.path_limits.push(PathLimit {
+            prefix: prefix.to_owned(),
+            rate: rate,
+            window_start: 0u64,
+            count: 0u,
+        });
     }
-}
 
-fn parse_id(rdr: @io::Reader) -> Result<~str, ~str> {
-    match read_str(rdr) {
-        Some(s) => Ok(s),
-        None => Err(~"invalid connection id"),
-    }
-}
+    // Counts a request against the most specific matching path limit and
+    // reports whether it went over.
+    fn throttle_at(&self, path: &str, now_ns: u64) -> bool {
+        let mut best = None;
 
-fn parse_path(rdr: @io::Reader) -> Result<~str, ~str> {
-    match read_str(rdr) {
-        Some(s) => Ok(s),
-        None => Err(~"invalid path"),
-    }
-}
+        for (i, limit) in self.path_limits.iter().enumerate() {
+            if path_under(path, limit.prefix).is_some() {
+                match best {
+                    Some(j) if self.path_limits[j].prefix.len() >= limit.prefix.len() => { },
+                    _ => best = Some(i),
+                }
+            }
+        }
 
-fn parse_headers(rdr: @io::Reader) -> Result<Headers, ~str> {
-    let tns = match tnetstring::from_reader(rdr) {
-        None => return Err(~"empty headers"),
-        Some(tns) => tns,
-    };
+        match best {
+            None => false,
+            Some(i) => {
+                let limit = &mut self.path_limits[i];
 
-    match tns {
-        tnetstring::Map(map) => parse_tnetstring_headers(map),
+                if now_ns - limit.window_start >= 1000000000u64 {
+                    limit.window_start = now_ns;
+                    limit.count = 0u;
+                }
 
-        // Fall back onto json if we got a string.
-        tnetstring::Str(bytes) => {
-            match json::from_str(str::from_bytes(bytes)) {
-                Err(e) => return Err(e.to_str()),
-                Ok(json::Object(map)) => parse_json_headers(map),
-                Ok(_) => Err(~"header is not a dictionary"),
+                limit.count += 1u;
+                limit.count > limit.rate
             }
         }
-
-        _ => Err(~"invalid header"),
     }
-}
 
-fn parse_tnetstring_headers(map: tnetstring::Map) -> Result<Headers, ~str> {
-    let mut headers = HashMap::new();
+    // Moves reading and parsing into a task of its own, which keeps up to
+    // `depth` requests waiting for recv. The task gets a scheduler to itself
+    // since it spends its time blocked in zmq. It takes the body size limit
+    // with it, so set that first.
+    pub fn enable_prefetch(&mut self, depth: uint) {
+        if self.prefetch.is_some() || depth == 0u { return; }
 
-    for (key, value) in map.iter() {
-        let key = str::from_bytes(*key);
-        let mut values = match headers.pop(&key) {
-            Some(values) => values,
-            None => ~[],
+        let standin = match self.ctx.socket(zmq::PULL) {
+            Ok(standin) => standin,
+            Err(e) => fail!(e.to_str()),
         };
+        let req = util::replace(&mut self.req, standin);
+        let max_body_size = self.max_body_size;
 
-        match value {
-            &tnetstring::Str(ref v) => values.push(str::from_bytes(*v)),
-            &tnetstring::Vec(ref vs) => {
-                for v in vs.iter() {
-                    match v {
-                        &tnetstring::Str(ref v) =>
-                            values.push(str::from_bytes(*v)),
-                        _ => return Err(~"header value is not a string"),
-                    }
-                }
-            },
-            _ => return Err(~"header value is not string"),
+        // term() stops the task through a PAIR socket, since it may be
+        // blocked waiting for a message.
+        let addr = fmt!("inproc://mongrel2-prefetch-%s", new_session_token(16u));
+        let control = match self.ctx.socket(zmq::PAIR) {
+            Ok(control) => control,
+            Err(e) => fail!(e.to_str()),
+        };
+        let task_control = match self.ctx.socket(zmq::PAIR) {
+            Ok(task_control) => task_control,
+            Err(e) => fail!(e.to_str()),
+        };
+        match control.bind(addr) {
+            Ok(()) => { },
+            Err(e) => fail!(e.to_str()),
+        }
+        match task_control.connect(addr) {
+            Ok(()) => { },
+            Err(e) => fail!(e.to_str()),
         }
 
-        headers.insert(key, values);
+        let (requests, requests_chan) = comm::stream();
+        let (credits_port, credits) = comm::stream();
+        let (stopped, stopped_chan) = comm::stream();
+
+        let mut builder = task::task();
+        builder.sched_mode(task::SingleThreaded);
+        builder.unlinked();
+        do builder.spawn {
+            prefetch(req, task_control, depth, max_body_size, requests_chan,
+                     credits_port);
+            stopped_chan.send(());
+        }
+
+        self.prefetch = Some(@Prefetch {
+            requests: requests,
+            credits: credits,
+            control: control,
+            stopped: stopped,
+        });
     }
 
-    Ok(headers)
-}
+    // Keeps up to `size` body buffers for reuse. Hand each request back with
+    // recycle once it has been answered. The prefetch task parses without
+    // the pool.
+    pub fn enable_buffer_pool(&mut self, size: uint) {
+        self.buffer_pool = Some(@mut BufferPool {
+            buffers: ~[],
+            size: size,
+            reuses: 0u,
+        });
+    }
 
-fn parse_json_headers(map: ~json::Object) -> Result<Headers, ~str> {
-    let mut headers = HashMap::new();
+    pub fn buffer_reuses(&self) -> uint {
+        match self.buffer_pool {
+            None => 0u,
+            Some(pool) => pool.reuses,
+        }
+    }
 
-    for (key, value) in map.iter() {
-        let mut values = match headers.pop(key) {
-            Some(values) => values,
+    pub fn recycle(&self, req: Request) {
+        match self.buffer_pool {
+            None => { },
+            Some(pool) => pool.give(req.body),
+        }
+    }
+
+    fn parse_message(&self, bytes: &[u8]) -> Result<Request, ~str> {
+        let body = match self.buffer_pool {
+            None => ~[],
+            Some(pool) => pool.take(),
+        };
+
+        parse_limited(bytes, self.max_body_size, body)
+    }
+
+    // Mongrel2 only delivers to MAX_IDS_PER_MESSAGE connections per
+    // message, so longer id lists go out as several messages.
+    pub fn send(&self,
+            uuid: &str,
+            id: &[~str],
+            body: &[u8]) -> Result<(), ~str> {
+        if id.len() <= MAX_IDS_PER_MESSAGE {
+            return self.send_message(uuid, id, body);
+        }
+
+        let mut start = 0u;
+        while start < id.len() {
+            let end = uint::min(start + MAX_IDS_PER_MESSAGE, id.len());
+            match self.send_message(uuid, id.slice(start, end), body) {
+                Ok(()) => { },
+                Err(e) => return Err(e),
+            }
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    // Sends one message to every id in `ids`, refusing lists Mongrel2 would
+    // not deliver in full rather than splitting them like `send` does.
+    pub fn reply_batch(&self,
+                       uuid: &str,
+                       ids: &[~str],
+                       body: &[u8]) -> Result<(), ~str> {
+        if ids.len() > MAX_IDS_PER_MESSAGE {
+            return Err(fmt!("cannot batch more than %u connection ids",
+                            MAX_IDS_PER_MESSAGE));
+        }
+
+        self.send_message(uuid, ids, body)
+    }
+
+    fn send_message(&self,
+                    uuid: &str,
+                    id: &[~str],
+                    body: &[u8]) -> Result<(), ~str> {
+        let id = str_as_bytes(id.connect(" "));
+
+        let mut msg = ~[];
+
+        msg.push_all(uuid.as_bytes());
+        msg.push(' ' as u8);
+        msg.push_all(tnetstring::to_bytes(&tnetstring::Str(id)));
+        msg.push(' ' as u8);
+        msg.push_all(body);
+
+        record_sent(msg);
+
+        match self.rep.send(msg, 0) {
+          Err(e) => Err(e.to_str()),
+          Ok(()) => Ok(()),
+        }
+    }
+
+    // An empty message tells Mongrel2 to close the client's connection.
+    pub fn close_connection(&self, req: &Request) -> Result<(), ~str> {
+        self.send(req.uuid, [req.id.clone()], [])
+    }
+
+    // Installs `f` to be called with every request as it is received,
+    // before any throttling.
+    pub fn on_request_start(&mut self, f: @fn(&Request)) {
+        self.hooks.start = Some(f);
+    }
+
+    // Installs `f` to be called once a request has been answered. Interim
+    // responses and the pieces of a chunked reply before its end don't
+    // count.
+    pub fn on_request_end(&mut self, f: @fn(&Request)) {
+        self.hooks.end = Some(f);
+    }
+
+    pub fn reply(&self, req: &Request, body: &[u8]) -> Result<(), ~str> {
+        let result = self.send_reply(req, body, true);
+
+        match self.hooks.end {
+            Some(f) => f(req),
+            None => { },
+        }
+
+        result
+    }
+
+    // Sends part of a reply without marking the request as answered.
+    fn reply_part(&self, req: &Request, body: &[u8]) -> Result<(), ~str> {
+        self.send_reply(req, body, false)
+    }
+
+    fn send_reply(&self, req: &Request, body: &[u8],
+                  finished: bool) -> Result<(), ~str> {
+        match (self.ordered_replies, req.sequence) {
+            (Some(ordered), Some(sequence)) => {
+                let ready = ordered.complete(req.id, sequence, body, finished);
+                for body in ready.iter() {
+                    match self.send(req.uuid, [req.id.clone()], *body) {
+                        Ok(()) => { },
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            }
+            _ => self.send(req.uuid, [req.id.clone()], body),
+        }
+    }
+
+    pub fn stash_reply(&self, req: &Request) -> ~str {
+        let token = fmt!("%u", self.stash.next_token);
+        self.stash.next_token += 1u;
+        self.stash.targets.insert(token.clone(), (req.uuid.clone(), req.id.clone()));
+        token
+    }
+
+    pub fn reply_by_token(&self, token: &str, body: &[u8]) -> Result<(), ~str> {
+        match self.stash.targets.pop(&token.to_owned()) {
+            None => Err(fmt!("unknown reply token: %s", token)),
+            Some((uuid, id)) => self.send(uuid, [id], body),
+        }
+    }
+
+    pub fn set_max_body_size(&mut self, max: uint) {
+        self.max_body_size = Some(max);
+    }
+
+    pub fn set_header_case(&mut self, policy: HeaderCase) {
+        self.header_case = policy;
+    }
+
+    // The file descriptor zmq signals on when the request socket may have
+    // messages, for use with an outside event loop. It is edge triggered:
+    // once it fires, keep calling recv_nonblock until it returns `None`.
+    pub fn req_fd(&self) -> Result<libc::c_int, ~str> {
+        if self.prefetch.is_some() {
+            return Err(~"the request socket belongs to the prefetch task");
+        }
+
+        match self.req.get_fd() {
+            Ok(fd) => Ok(fd as libc::c_int),
+            Err(e) => Err(e.to_str()),
+        }
+    }
+
+    // Waits up to `timeout_ms` for a request to be ready on the socket
+    // behind req_fd. The fd only says zmq has something to look at, so
+    // ZMQ_EVENTS decides whether a message is really waiting. A negative
+    // timeout waits for as long as it takes, as with recv_timeout.
+    pub fn wait_readable(&self, timeout_ms: int) -> bool {
+        let fd = match self.req_fd() {
+            Ok(fd) => fd,
+            Err(_) => return false,
+        };
+
+        let forever = timeout_ms < 0;
+        let deadline = if forever {
+            0u64
+        } else {
+            time::precise_time_ns() + timeout_ms as u64 * 1000000u64
+        };
+
+        loop {
+            match self.req.get_events() {
+                Ok(events) if events as i64 & zmq::POLLIN as i64 != 0 => return true,
+                Ok(_) => { },
+                Err(_) => return false,
+            }
+
+            let remaining_ms = if forever {
+                -1
+            } else {
+                let now = time::precise_time_ns();
+                if now >= deadline { return false; }
+                ((deadline - now + 999999u64) / 1000000u64) as libc::c_int
+            };
+
+            let mut pollfd = posix::PollFd { fd: fd, events: posix::POLLIN, revents: 0 };
+            let ready = unsafe {
+                posix::poll(&mut pollfd, 1 as libc::c_ulong, remaining_ms)
+            };
+            if ready < 0 { return false; }
+        }
+    }
+
+    // Writes response headers sorted by name rather than in hash order, for
+    // byte for byte reproducible replies.
+    pub fn set_sorted_headers(&mut self, sorted: bool) {
+        self.sorted_headers = sorted;
+    }
+
+    // Bodies smaller than this are never compressed by
+    // reply_http_compressed.
+    pub fn set_compress_threshold(&mut self, bytes: uint) {
+        self.compress_threshold = bytes;
+    }
+
+    pub fn enable_ordered_replies(&mut self) {
+        self.ordered_replies = Some(@mut OrderedReplies {
+            next_sequence: 0u,
+            pending: HashMap::new(),
+        });
+    }
+
+    pub fn reply_response(&self, req: &Request, resp: &Response) -> Result<(), ~str> {
+        self.reply_http_bytes(req, resp.code, resp.status, &resp.headers, resp.body)
+    }
+
+    pub fn reply_http(&self,
+                  req: &Request,
+                  code: uint,
+                  status: &str,
+                  headers: Headers,
+                  body: ~str) -> Result<(), ~str> {
+        self.reply_http_bytes(req, code, status, &headers, str_as_bytes(body))
+    }
+
+    // Like reply_http, but gzips the body when the client accepts it. Small
+    // bodies and bodies the caller already gave a Content-Encoding are sent
+    // as they are.
+    pub fn reply_http_compressed(&self,
+                                 req: &Request,
+                                 code: uint,
+                                 status: &str,
+                                 headers: Headers,
+                                 body: ~str) -> Result<(), ~str> {
+        let body = str_as_bytes(body);
+
+        let mut encoded = false;
+        for (name, _) in headers.iter() {
+            if name.eq_ignore_ascii_case("content-encoding") { encoded = true; }
+        }
+        if encoded || body.len() < self.compress_threshold {
+            return self.reply_http_bytes(req, code, status, &headers, body);
+        }
+
+        let mut headers = headers;
+        add_vary(&mut headers, "Accept-Encoding");
+
+        if req.accepts_encoding("gzip") {
+            set_header(&mut headers, "Content-Encoding", ~"gzip");
+            self.reply_http_bytes(req, code, status, &headers, gzip(body))
+        } else {
+            self.reply_http_bytes(req, code, status, &headers, body)
+        }
+    }
+
+    // Relays a complete upstream HTTP response, leaving out the hop-by-hop
+    // headers that only applied to the upstream connection. A chunked body
+    // is decoded and sent with a Content-Length instead.
+    pub fn reply_raw_http(&self, req: &Request, response: &[u8]) -> Result<(), ~str> {
+        match strip_hop_headers(response) {
+            Ok(response) => self.reply(req, response),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn reply_http_bytes(&self,
+                        req: &Request,
+                        code: uint,
+                        status: &str,
+                        headers: &Headers,
+                        body: &[u8]) -> Result<(), ~str> {
+        let mut rep = http_head(req.response_version(), code, status, headers,
+                                Some(body.len()), self.header_case,
+                                self.sorted_headers);
+        rep.push_all(body);
+
+        self.reply(req, rep)
+    }
+
+    // Streams the body in chunks pulled from `gen` until it returns `None`.
+    pub fn reply_http_stream(&self,
+                             req: &Request,
+                             code: uint,
+                             status: &str,
+                             headers: Headers,
+                             gen: &fn() -> Option<~[u8]>) -> Result<(), ~str> {
+        match self.reply_http_start(req, code, status, headers) {
+            Ok(()) => { },
+            Err(e) => return Err(e),
+        }
+
+        loop {
+            match gen() {
+                None => break,
+                Some(data) => {
+                    match self.reply_http_chunk(req, data) {
+                        Ok(()) => { },
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+
+        self.reply_http_end(req)
+    }
+
+    // Sends the head of a chunked response. The body follows in any number
+    // of reply_http_chunk calls and is finished off by reply_http_end.
+    // HTTP/1.0 has no chunked encoding, so those clients get the body as
+    // is, ended by closing the connection.
+    pub fn reply_http_start(&self,
+                            req: &Request,
+                            code: uint,
+                            status: &str,
+                            headers: Headers) -> Result<(), ~str> {
+        let mut headers = headers;
+        if req.response_version() == "HTTP/1.0" {
+            set_header(&mut headers, "Connection", ~"close");
+        } else {
+            set_header(&mut headers, "Transfer-Encoding", ~"chunked");
+        }
+
+        self.reply_part(req, http_head(req.response_version(), code, status,
+                                       &headers, None, self.header_case,
+                                       self.sorted_headers))
+    }
+
+    pub fn reply_http_chunk(&self, req: &Request, data: &[u8]) -> Result<(), ~str> {
+        // An empty chunk would end the stream early.
+        if data.is_empty() { return Ok(()); }
+
+        if req.response_version() == "HTTP/1.0" {
+            self.reply_part(req, data)
+        } else {
+            self.reply_part(req, http_chunk(data))
+        }
+    }
+
+    // An empty message has Mongrel2 close the connection.
+    pub fn reply_http_end(&self, req: &Request) -> Result<(), ~str> {
+        if req.response_version() == "HTTP/1.0" {
+            self.reply(req, [])
+        } else {
+            self.reply(req, "0\r\n\r\n".as_bytes())
+        }
+    }
+
+    // Begins a server-sent event stream as a chunked 200. With `compress`
+    // set and a client that accepts gzip, every event is sent as a gzip
+    // member of its own. Members may follow one another in a gzip stream,
+    // and nothing is held back in a compressor, so each event still reaches
+    // the client as soon as it is sent.
+    pub fn reply_sse_start(&self,
+                           req: &Request,
+                           headers: Headers,
+                           compress: bool) -> Result<SseStream, ~str> {
+        let mut headers = headers;
+        set_header(&mut headers, "Content-Type", ~"text/event-stream");
+        set_header(&mut headers, "Cache-Control", ~"no-cache");
+
+        let gzip = compress && req.accepts_encoding("gzip");
+        if compress { add_vary(&mut headers, "Accept-Encoding"); }
+        if gzip { set_header(&mut headers, "Content-Encoding", ~"gzip"); }
+
+        match self.reply_http_start(req, 200u, status_reason(200u), headers) {
+            Ok(()) => Ok(SseStream { gzip: gzip }),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Sends an event, with an "id:" field for the client to hand back as
+    // Last-Event-ID when it reconnects. Each line of `data` becomes a
+    // "data:" field. Line breaks in `id`, or a CR in `data`, would start
+    // fields of their own, so they are refused.
+    pub fn reply_sse_event(&self,
+                           req: &Request,
+                           stream: &SseStream,
+                           id: Option<&str>,
+                           data: &str) -> Result<(), ~str> {
+        let mut event = ~"";
+        match id {
+            Some(id) if id.iter().any(|c| c == '\r' || c == '\n' || c == '\x00') =>
+                return Err(~"invalid event id"),
+            Some(id) => event.push_str(fmt!("id: %s\n", id)),
+            None => { },
+        }
+
+        if data.contains_char('\r') { return Err(~"invalid event data"); }
+        for line in data.split_iter('\n') {
+            event.push_str(fmt!("data: %s\n", line));
+        }
+        event.push_char('\n');
+
+        self.reply_sse_part(req, stream, str_as_bytes(event))
+    }
+
+    // Sends the "retry:" field, telling the browser how long to wait before
+    // reconnecting.
+    pub fn reply_sse_retry(&self, req: &Request, stream: &SseStream, ms: uint) -> Result<(), ~str> {
+        self.reply_sse_part(req, stream, str_as_bytes(fmt!("retry: %u\n\n", ms)))
+    }
+
+    fn reply_sse_part(&self, req: &Request, stream: &SseStream, data: &[u8]) -> Result<(), ~str> {
+        if stream.gzip {
+            self.reply_http_chunk(req, gzip(data))
+        } else {
+            self.reply_http_chunk(req, data)
+        }
+    }
+
+    pub fn reply_status(&self,
+                        req: &Request,
+                        code: uint,
+                        headers: Headers) -> Result<(), ~str> {
+        // Informational, 204 and 304 responses never carry a body, so they
+        // must not advertise a length either.
+        let content_length = if code < 200u || code == 204u || code == 304u {
+            None
+        } else {
+            Some(0u)
+        };
+
+        let head = http_head(req.response_version(), code, status_reason(code),
+                             &headers, content_length, self.header_case,
+                             self.sorted_headers);
+
+        // Apart from a 101, an informational response is followed by the
+        // real one.
+        if code < 200u && code != 101u {
+            self.reply_part(req, head)
+        } else {
+            self.reply(req, head)
+        }
+    }
+
+    // Sends an interim 100 response telling the client how much of its
+    // upload has arrived, so an interrupted upload can resume from there.
+    pub fn ack_upload_chunk(&self,
+                            req: &Request,
+                            bytes_received: uint) -> Result<(), ~str> {
+        let mut headers = Headers();
+        set_header(&mut headers, "Upload-Offset", uint::to_str(bytes_received));
+
+        self.reply_status(req, 100u, headers)
+    }
+
+    // Wraps the JSON in a call to the function named by the `callback_param`
+    // query parameter. Anything but a plain (possibly dotted) identifier is
+    // refused with a 400, since the name is echoed into a script.
+    pub fn reply_jsonp(&self,
+                       req: &Request,
+                       callback_param: &str,
+                       value: &json::Json) -> Result<(), ~str> {
+        let callback = match req.query_param(callback_param) {
+            Some(callback) if is_safe_callback(callback) => callback,
+            _ => return self.reply_http(req, 400u, status_reason(400u), Headers(),
+                                        ~"invalid JSONP callback"),
+        };
+
+        let mut headers = Headers();
+        set_header(&mut headers, "Content-Type", ~"application/javascript");
+        set_header(&mut headers, "X-Content-Type-Options", ~"nosniff");
+
+        self.reply_http(req, 200u, "OK", headers,
+                        fmt!("%s(%s)", callback, value.to_str()))
+    }
+
+    // Answers a request with its own body and content type.
+    pub fn echo(&self, req: &Request) -> Result<(), ~str> {
+        let mut headers = Headers();
+        match req.header_first("content-type") {
+            Some(content_type) => set_header(&mut headers, "Content-Type", content_type),
+            None => { },
+        }
+
+        self.reply_http_bytes(req, 200u, "OK", &headers, req.body)
+    }
+
+    // Completes a WebSocket handshake, agreeing on the first subprotocol the
+    // client offered that is also in `protocols`. Returns the subprotocol it
+    // settled on, if any.
+    pub fn websocket_accept(&self,
+                            req: &Request,
+                            protocols: &[~str]) -> Result<Option<~str>, ~str> {
+        match self.websocket_handshake(req, protocols, false) {
+            Ok((protocol, _)) => Ok(protocol),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Like websocket_accept, but also takes up a permessage-deflate offer
+    // from the client. The returned flag says whether the extension is in
+    // use, in which case messages go through ws_deflate and ws_inflate.
+    pub fn websocket_accept_deflate(&self,
+                                    req: &Request,
+                                    protocols: &[~str]) -> Result<(Option<~str>, bool), ~str> {
+        self.websocket_handshake(req, protocols, true)
+    }
+
+    fn websocket_handshake(&self,
+                           req: &Request,
+                           protocols: &[~str],
+                           deflate: bool) -> Result<(Option<~str>, bool), ~str> {
+        let key = match req.header_first("sec-websocket-key") {
+            Some(key) => key,
+            None => return Err(~"missing Sec-WebSocket-Key header"),
+        };
+
+        let offered = match req.header_list("sec-websocket-protocol") {
+            Some(offered) => offered,
             None => ~[],
         };
+        let protocol = negotiate_subprotocol(offered, protocols);
+
+        let deflate = deflate && match req.header_list("sec-websocket-extensions") {
+            Some(extensions) => extensions.iter().any(|offer| accepts_deflate_offer(*offer)),
+            None => false,
+        };
+
+        let mut headers = Headers();
+        set_header(&mut headers, "Upgrade", ~"websocket");
+        set_header(&mut headers, "Connection", ~"Upgrade");
+        set_header(&mut headers, "Sec-WebSocket-Accept", websocket_accept_key(key));
+        match protocol {
+            Some(ref protocol) =>
+                set_header(&mut headers, "Sec-WebSocket-Protocol", protocol.clone()),
+            None => { },
+        }
+        if deflate {
+            set_header(&mut headers, "Sec-WebSocket-Extensions",
+                       ~"permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+        }
+
+        match self.reply_status(req, 101u, headers) {
+            Ok(()) => Ok((protocol, deflate)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Sends a whole text message to the client of an upgraded connection.
+    pub fn send_ws_text(&self, req: &Request, text: &str) -> Result<(), ~str> {
+        self.send_ws_message(req, WS_TEXT, text.as_bytes())
+    }
+
+    pub fn send_ws_binary(&self, req: &Request, data: &[u8]) -> Result<(), ~str> {
+        self.send_ws_message(req, WS_BINARY, data)
+    }
+
+    fn send_ws_message(&self, req: &Request, opcode: u8, payload: &[u8]) -> Result<(), ~str> {
+        let frame = WsFrame {
+            fin: true,
+            compressed: false,
+            opcode: opcode,
+            payload: payload.to_owned(),
+        };
+
+        self.send(req.uuid, [req.id.clone()], encode_ws_frame(&frame, WsServer))
+    }
+
+    // Declines a WebSocket upgrade with an ordinary HTTP error in place of
+    // the 101 handshake.
+    pub fn reject_websocket(&self,
+                            req: &Request,
+                            code: uint,
+                            reason: &str) -> Result<(), ~str> {
+        let mut headers = Headers();
+        set_header(&mut headers, "Connection", ~"close");
+        set_header(&mut headers, "Content-Type", ~"text/plain; charset=utf-8");
+
+        self.reply_http(req, code, status_reason(code), headers, reason.to_owned())
+    }
+
+    pub fn reply_request_timeout(&self, req: &Request) -> Result<(), ~str> {
+        let mut headers = Headers();
+        set_header(&mut headers, "Connection", ~"close");
+
+        self.reply_http(req, 408u, status_reason(408u), headers, ~"")
+    }
+
+    pub fn reply_precondition_failed(&self, req: &Request) -> Result<(), ~str> {
+        self.reply_http(req, 412u, status_reason(412u), Headers(), ~"")
+    }
+
+    // Refuses a request whose Host isn't one this handler serves.
+    pub fn reply_bad_host(&self, req: &Request) -> Result<(), ~str> {
+        let mut headers = Headers();
+        set_header(&mut headers, "Content-Type", ~"text/plain; charset=utf-8");
+
+        self.reply_http(req, 400u, status_reason(400u), headers, ~"invalid Host header")
+    }
+
+    // Refuses a Range the handler can't satisfy for a representation of
+    // `length` units, in whatever unit the client asked for.
+    pub fn reply_range_not_satisfiable(&self, req: &Request, length: uint) -> Result<(), ~str> {
+        let unit = match req.range_unit() {
+            Some(unit) => unit,
+            None => ~"bytes",
+        };
+
+        let mut headers = Headers();
+        set_header(&mut headers, "Content-Range", fmt!("%s */%u", unit, length));
+
+        self.reply_http(req, 416u, status_reason(416u), headers, ~"")
+    }
+
+    // Tells the client it must switch to `upgrade_to`, such as "TLS/1.2",
+    // before the request can be served. Nothing is sent if `upgrade_to` is
+    // empty or holds control characters, which could split the response.
+    pub fn reply_upgrade_required(&self, req: &Request, upgrade_to: &str) -> Result<(), ~str> {
+        if upgrade_to.is_empty() || !upgrade_to.iter().all(|c| header_value_char(c)) {
+            return Err(~"invalid Upgrade protocol");
+        }
+
+        let mut headers = Headers();
+        set_header(&mut headers, "Upgrade", upgrade_to.to_owned());
+        set_header(&mut headers, "Connection", ~"Upgrade");
+
+        self.reply_http(req, 426u, status_reason(426u), headers, ~"")
+    }
+
+    // For a request that reached a handler not configured for its host,
+    // such as one sent over a reused TLS connection. The client may retry it
+    // on a new connection.
+    pub fn reply_misdirected(&self, req: &Request) -> Result<(), ~str> {
+        let mut headers = Headers();
+        set_header(&mut headers, "Connection", ~"close");
+
+        self.reply_http(req, 421u, status_reason(421u), headers, ~"")
+    }
+
+    // Runs a simple server loop, answering each request with whatever
+    // `handler` returns. Mongrel2's disconnect and flow control messages
+    // are skipped, and so are malformed messages, whose clients have been
+    // sent a 400 where possible. Only socket errors, such as the context
+    // being terminated, end the loop. `handler` is a plain fn rather than a
+    // closure because every request runs it in a task of its own, which a
+    // managed closure can't be sent to.
+    pub fn serve(&self, handler: extern fn(Request) -> Response) -> Result<(), ~str> {
+        loop {
+            match self.read_message(true) {
+                Ok(Some(request)) => {
+                    if request.is_disconnect() || request.is_flow_control() { loop; }
+
+                    match self.serve_one(handler, request) {
+                        Ok(()) => { },
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(None) => { },
+                Err(Malformed(e, _)) => error!("skipping malformed message: %s", e),
+                Err(SocketError(e)) => return Err(e),
+            }
+        }
+    }
+
+    // Calls `handler` in a task of its own, so a handler that fails gets
+    // its client a 500 instead of taking the server down.
+    fn serve_one(&self,
+                 handler: extern fn(Request) -> Response,
+                 req: Request) -> Result<(), ~str> {
+        let target = req.reply_target();
+
+        let result = do task::try { handler(req) };
+
+        match result {
+            Ok(response) => self.reply_response(&target, &response),
+            Err(()) => self.reply_http(&target, 500u, status_reason(500u), Headers(), ~""),
+        }
+    }
+
+    // With prefetch on, waits for the prefetch task to close the socket it
+    // reads from, so the context can be terminated afterwards.
+    pub fn term (&mut self) {
+        match self.prefetch {
+            Some(prefetch) => {
+                // The spare credit wakes a task that is waiting for one.
+                prefetch.control.send([], 0);
+                prefetch.credits.try_send(());
+                prefetch.stopped.try_recv();
+                prefetch.control.close();
+            }
+            None => { },
+        }
+        self.prefetch = None;
+
+        self.req.close();
+        self.rep.close();
+    }
+}
+
+mod posix {
+    use std::libc::{c_int, c_short, c_ulong};
+
+    pub static POLLIN: c_short = 1;
+
+    pub struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    extern {
+        pub fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    }
+}
+
+fn prefetch(req: zmq::Socket,
+            control: zmq::Socket,
+            depth: uint,
+            max_body_size: Option<uint>,
+            requests: Chan<Result<Request, ReadError>>,
+            credits: Port<()>) {
+    let mut available = depth;
+
+    loop {
+        if available == 0u {
+            match credits.try_recv() {
+                Some(()) => available += 1u,
+                None => break,
+            }
+        }
+
+        let mut items = [req.as_poll_item(zmq::POLLIN),
+                         control.as_poll_item(zmq::POLLIN)];
+        match zmq::poll(items, -1) {
+            Ok(()) => { },
+            Err(_) => break,
+        }
+
+        match unsafe { control.recv(zmq::DONTWAIT) } {
+            Ok(_) => break,
+            Err(_) => { },
+        }
+
+        let request = match unsafe { req.recv(zmq::DONTWAIT) } {
+            Err(zmq::EAGAIN) => None,
+            Err(zmq::ETERM) => break,
+            Err(e) => Some(Err(SocketError(fmt!("recv failed: %s", e.to_str())))),
+            Ok(msg) => Some(msg.with_bytes(|bytes| {
+                match parse_limited(bytes, max_body_size, ~[]) {
+                    Ok(request) => Ok(request),
+                    Err(e) => Err(Malformed(e, message_target(bytes))),
+                }
+            })),
+        };
+
+        match request {
+            None => { },
+            Some(request) => {
+                if !requests.try_send(request) { break; }
+                available -= 1u;
+            }
+        }
+    }
+
+    req.close();
+    control.close();
+}
+
+// Talks to the Mongrel2 control port, which answers tnetstring encoded
+// commands over a REQ/REP socket.
+pub struct ControlConnection {
+    addr: ~str,
+    socket: zmq::Socket,
+}
+
+pub fn connect_control(ctx: zmq::Context, addr: ~str) -> ControlConnection {
+    let socket = match ctx.socket(zmq::REQ) {
+        Ok(socket) => socket,
+        Err(e) => fail!(e.to_str()),
+    };
+
+    match socket.connect(addr) {
+        Ok(()) => { },
+        Err(e) => fail!(e.to_str()),
+    }
+
+    ControlConnection { addr: addr, socket: socket }
+}
+
+impl ControlConnection {
+    pub fn command(&self,
+                   name: &str,
+                   args: &[(~str, ~str)]) -> Result<tnetstring::TNetString, ~str> {
+        match self.socket.send(control_command(name, args), 0) {
+            Ok(()) => { },
+            Err(e) => return Err(e.to_str()),
+        }
+
+        match unsafe { self.socket.recv(0) } {
+            Err(e) => Err(e.to_str()),
+            Ok(msg) => {
+                do msg.with_bytes |bytes| {
+                    match io::with_bytes_reader(bytes, tnetstring::from_reader) {
+                        Some(reply) => Ok(reply),
+                        None => Err(~"invalid control reply"),
+                    }
+                }
+            }
+        }
+    }
+
+    // `what` is either "tasks" or "net".
+    pub fn status(&self, what: &str) -> Result<tnetstring::TNetString, ~str> {
+        self.command("status", [(~"what", what.to_owned())])
+    }
+
+    pub fn kill(&self, id: uint) -> Result<tnetstring::TNetString, ~str> {
+        self.command("kill", [(~"id", uint::to_str(id))])
+    }
+
+    pub fn term(&mut self) {
+        self.socket.close();
+    }
+}
+
+// Control commands are a tnetstring list of the command name followed by a
+// dictionary of its arguments.
+fn control_command(name: &str, args: &[(~str, ~str)]) -> ~[u8] {
+    let mut map = HashMap::new();
+    for arg in args.iter() {
+        let (ref key, ref value) = *arg;
+        map.insert(str::to_bytes(*key), tnetstring::Str(str::to_bytes(*value)));
+    }
+
+    tnetstring::to_bytes(&tnetstring::Vec(~[
+        tnetstring::Str(str::to_bytes(name)),
+        tnetstring::Map(map),
+    ]))
+}
+
+pub fn status_reason(code: uint) -> &'static str {
+    match code {
+        100u => "Continue",
+        101u => "Switching Protocols",
+        200u => "OK",
+        201u => "Created",
+        202u => "Accepted",
+        203u => "Non-Authoritative Information",
+        204u => "No Content",
+        205u => "Reset Content",
+        206u => "Partial Content",
+        300u => "Multiple Choices",
+        301u => "Moved Permanently",
+        302u => "Found",
+        303u => "See Other",
+        304u => "Not Modified",
+        305u => "Use Proxy",
+        307u => "Temporary Redirect",
+        308u => "Permanent Redirect",
+        400u => "Bad Request",
+        401u => "Unauthorized",
+        402u => "Payment Required",
+        403u => "Forbidden",
+        404u => "Not Found",
+        405u => "Method Not Allowed",
+        406u => "Not Acceptable",
+        407u => "Proxy Authentication Required",
+        408u => "Request Timeout",
+        409u => "Conflict",
+        410u => "Gone",
+        411u => "Length Required",
+        412u => "Precondition Failed",
+        413u => "Payload Too Large",
+        414u => "URI Too Long",
+        415u => "Unsupported Media Type",
+        416u => "Range Not Satisfiable",
+        417u => "Expectation Failed",
+        421u => "Misdirected Request",
+        422u => "Unprocessable Entity",
+        426u => "Upgrade Required",
+        428u => "Precondition Required",
+        429u => "Too Many Requests",
+        431u => "Request Header Fields Too Large",
+        500u => "Internal Server Error",
+        501u => "Not Implemented",
+        502u => "Bad Gateway",
+        503u => "Service Unavailable",
+        504u => "Gateway Timeout",
+        505u => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+pub enum HeaderCase {
+    PreserveCase,
+    LowerCase,
+    TitleCase,
+}
+
+fn header_name(name: &str, case: HeaderCase) -> ~str {
+    match case {
+        PreserveCase => name.to_owned(),
+        LowerCase => name.to_ascii_lower(),
+        TitleCase => {
+            let words: ~[~str] = name.split_iter('-').map(|word| {
+                if word.is_empty() {
+                    ~""
+                } else {
+                    // The first character may take more than one byte.
+                    let str::CharRange { ch, next } = word.char_range_at(0u);
+                    str::from_char(ch).to_ascii_upper() + word.slice_from(next).to_ascii_lower()
+                }
+            }).collect();
+            words.connect("-")
+        }
+    }
+}
+
+fn http_head(version: &str,
+             code: uint,
+             status: &str,
+             headers: &Headers,
+             content_length: Option<uint>,
+             case: HeaderCase,
+             sorted: bool) -> ~[u8] {
+    let mut rep = ~[];
+
+    rep.push_all(str_as_bytes(fmt!("%s %u ", version, code)));
+    rep.push_all(status.as_bytes());
+    rep.push_all("\r\n".as_bytes());
+
+    match content_length {
+        None => { },
+        Some(len) => {
+            rep.push_all(str_as_bytes(header_name("Content-Length", case)));
+            rep.push_all(": ".as_bytes());
+            rep.push_all(str_as_bytes(uint::to_str(len)));
+            rep.push_all("\r\n".as_bytes());
+        }
+    }
+
+    let mut keys = ~[];
+    for (key, _) in headers.iter() { keys.push(key); }
+    if sorted {
+        sort::quick_sort(keys, |a, b| a.to_ascii_lower() <= b.to_ascii_lower());
+    }
+
+    for key in keys.iter() {
+        let name = header_name(key.as_slice(), case);
+        for value in headers.get(*key).iter() {
+            rep.push_all(str_as_bytes(name + ": " + *value + "\r\n"));
+        };
+    }
+    rep.push_all("\r\n".as_bytes());
+
+    rep
+}
+
+// A server-sent event stream begun by reply_sse_start, remembering whether
+// its events are gzipped. The stream is finished off by reply_http_end.
+pub struct SseStream {
+    priv gzip: bool,
+}
+
+fn http_chunk(data: &[u8]) -> ~[u8] {
+    let mut chunk = str_as_bytes(fmt!("%x\r\n", data.len()));
+    chunk.push_all(data);
+    chunk.push_all("\r\n".as_bytes());
+    chunk
+}
+
+pub static WS_CONTINUATION: u8 = 0x0;
+pub static WS_TEXT: u8 = 0x1;
+pub static WS_BINARY: u8 = 0x2;
+pub static WS_CLOSE: u8 = 0x8;
+pub static WS_PING: u8 = 0x9;
+pub static WS_PONG: u8 = 0xA;
+
+#[deriving(Clone, Eq)]
+pub struct WsFrame {
+    fin: bool,
+    compressed: bool,
+    opcode: u8,
+    payload: ~[u8],
+}
+
+// Decodes one WebSocket frame, unmasking the payload if needed, and returns
+// it along with the number of bytes it took up.
+pub fn decode_ws_frame(bytes: &[u8]) -> Result<(WsFrame, uint), ~str> {
+    if bytes.len() < 2u { return Err(~"truncated websocket frame"); }
+
+    let fin = bytes[0u] & 0x80u8 != 0u8;
+    let compressed = bytes[0u] & 0x40u8 != 0u8;
+    let opcode = bytes[0u] & 0x0fu8;
+    let masked = bytes[1u] & 0x80u8 != 0u8;
+    let mut pos = 2u;
+
+    let len = match bytes[1u] & 0x7fu8 {
+        126u8 => {
+            if bytes.len() < pos + 2u { return Err(~"truncated websocket frame"); }
+            pos += 2u;
+            (bytes[2u] as uint << 8u) | bytes[3u] as uint
+        }
+        127u8 => {
+            if bytes.len() < pos + 8u { return Err(~"truncated websocket frame"); }
+            let mut len = 0u64;
+            for i in range(2u, 10u) {
+                len = (len << 8u64) | bytes[i] as u64;
+            }
+            pos += 8u;
+            if len > uint::max_value as u64 {
+                return Err(~"websocket frame too large");
+            }
+            len as uint
+        }
+        len => len as uint,
+    };
+
+    let mask = if masked {
+        if bytes.len() < pos + 4u { return Err(~"truncated websocket frame"); }
+        pos += 4u;
+        Some(bytes.slice(pos - 4u, pos))
+    } else {
+        None
+    };
+
+    if bytes.len() - pos < len { return Err(~"truncated websocket frame"); }
+
+    let mut payload = bytes.slice(pos, pos + len).to_owned();
+    match mask {
+        None => { },
+        Some(mask) => {
+            for i in range(0u, payload.len()) {
+                payload[i] ^= mask[i % 4u];
+            }
+        }
+    }
+
+    Ok((WsFrame { fin: fin, compressed: compressed, opcode: opcode, payload: payload },
+        pos + len))
+}
+
+// Frames sent by a server must not be masked, while a client masks every
+// frame with a key of its choosing.
+pub enum WsRole {
+    WsServer,
+    WsClient([u8, ..4]),
+}
+
+pub fn encode_ws_frame(frame: &WsFrame, role: WsRole) -> ~[u8] {
+    let mut bytes = ~[];
+    let len = frame.payload.len();
+
+    let fin_bit = if frame.fin { 0x80u8 } else { 0u8 };
+    let rsv1_bit = if frame.compressed { 0x40u8 } else { 0u8 };
+    bytes.push(fin_bit | rsv1_bit | (frame.opcode & 0x0fu8));
+
+    let mask_bit = match role {
+        WsServer => 0u8,
+        WsClient(_) => 0x80u8,
+    };
+
+    if len < 126u {
+        bytes.push(mask_bit | len as u8);
+    } else if len <= 0xffffu {
+        bytes.push(mask_bit | 126u8);
+        bytes.push((len >> 8u) as u8);
+        bytes.push(len as u8);
+    } else {
+        bytes.push(mask_bit | 127u8);
+        for i in range(0u, 8u) {
+            bytes.push(((len as u64) >> ((56u - i * 8u) as u64)) as u8);
+        }
+    }
+
+    match role {
+        WsServer => bytes.push_all(frame.payload),
+        WsClient(mask) => {
+            bytes.push_all(mask);
+            for (i, b) in frame.payload.iter().enumerate() {
+                bytes.push(*b ^ mask[i % 4u]);
+            }
+        }
+    }
+
+    bytes
+}
+
+pub fn websocket_accept_key(key: &str) -> ~str {
+    let mut digest = Sha1::new();
+    digest.input(key.trim().as_bytes());
+    digest.input("258EAFA5-E914-47DA-95CA-C5AB0DC85B11".as_bytes());
+    base64_encode(digest_result(&mut digest), false)
+}
+
+// Only offers we can honour with a fresh deflate stream per message and
+// the default window size are taken up.
+fn accepts_deflate_offer(offer: &str) -> bool {
+    let mut params = offer.split_iter(';').map(|p| p.trim());
+
+    match params.next() {
+        Some(name) if name.eq_ignore_ascii_case("permessage-deflate") => { },
+        _ => return false,
+    }
+
+    for param in params {
+        let (name, value) = match param.find('=') {
+            Some(i) => (param.slice_to(i).trim(), Some(param.slice_from(i + 1u).trim())),
+            None => (param, None),
+        };
+
+        match name.to_ascii_lower().as_slice() {
+            "server_no_context_takeover" | "client_no_context_takeover" |
+            "client_max_window_bits" => { },
+            "server_max_window_bits" => {
+                if value.map_default(true, |v| v.trim_chars(&'"') != "15") {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+// Wraps a raw deflate stream in the gzip format of RFC 1952.
+fn gzip(bytes: &[u8]) -> ~[u8] {
+    let mut out = ~[0x1fu8, 0x8bu8, 8u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0xffu8];
+    out.push_all(flate::deflate_bytes(bytes));
+
+    let crc = crc32(bytes);
+    let len = bytes.len() as u32;
+    for i in range(0u, 4u) { out.push((crc >> (i * 8u) as u32) as u8); }
+    for i in range(0u, 4u) { out.push((len >> (i * 8u) as u32) as u8); }
+
+    out
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for b in bytes.iter() {
+        crc ^= *b as u32;
+        for _ in range(0u, 8u) {
+            crc = if crc & 1u32 != 0u32 { (crc >> 1u32) ^ 0xedb88320u32 } else { crc >> 1u32 };
+        }
+    }
+
+    !crc
+}
+
+// Compresses a message payload for a frame with the compressed bit set.
+// Each message is a complete deflate stream, so no context is carried over
+// between messages.
+pub fn ws_deflate(payload: &[u8]) -> ~[u8] {
+    let mut bytes = flate::deflate_bytes(payload);
+    if bytes_ends_with(bytes, [0x00u8, 0x00u8, 0xffu8, 0xffu8]) {
+        let len = bytes.len() - 4u;
+        bytes.truncate(len);
+    }
+    bytes
+}
+
+// Inflates a compressed message payload, giving up with a 1009 once the
+// output would pass `max_size`. extra::flate fails the task on corrupt
+// input and has no limit, so client payloads go through our own inflater.
+pub fn ws_inflate(payload: &[u8], max_size: uint) -> Result<~[u8], WsError> {
+    let mut bytes = payload.to_owned();
+    bytes.push_all([0x00u8, 0x00u8, 0xffu8, 0xffu8]);
+
+    match inflate::inflate(bytes, max_size) {
+        Ok(payload) => Ok(payload),
+        Err(inflate::TooLarge) =>
+            Err(ws_error(WS_CLOSE_MESSAGE_TOO_BIG, "websocket message too big")),
+        Err(inflate::Corrupt) =>
+            Err(ws_error(WS_CLOSE_INVALID_PAYLOAD, "invalid compressed websocket message")),
+    }
+}
+
+// A raw (RFC 1951) deflate decoder that stops at an output limit.
+mod inflate {
+    use std::vec;
+
+    #[deriving(Eq)]
+    pub enum Error {
+        Corrupt,
+        TooLarge,
+    }
+
+    static LENGTH_BASE: [uint, ..29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+        35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+    static LENGTH_EXTRA: [uint, ..29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+        3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+    static DIST_BASE: [uint, ..30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+        257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+        8193, 12289, 16385, 24577];
+    static DIST_EXTRA: [uint, ..30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+        7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+    static CODE_ORDER: [uint, ..19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+    struct Input<'self> {
+        bytes: &'self [u8],
+        pos: uint,
+        bitbuf: uint,
+        bitcnt: uint,
+    }
+
+    // A canonical Huffman code: how many codes there are of each length, and
+    // the symbols ordered by code.
+    struct Huffman {
+        counts: ~[uint],
+        symbols: ~[uint],
+    }
+
+    impl<'self> Input<'self> {
+        fn bits(&mut self, n: uint) -> Result<uint, Error> {
+            while self.bitcnt < n {
+                if self.pos >= self.bytes.len() { return Err(Corrupt); }
+                self.bitbuf |= (self.bytes[self.pos] as uint) << self.bitcnt;
+                self.pos += 1u;
+                self.bitcnt += 8u;
+            }
+
+            let value = self.bitbuf & ((1u << n) - 1u);
+            self.bitbuf >>= n;
+            self.bitcnt -= n;
+            Ok(value)
+        }
+
+        fn decode(&mut self, code: &Huffman) -> Result<uint, Error> {
+            let mut bits = 0u;
+            let mut first = 0u;
+            let mut index = 0u;
+
+            for len in range(1u, 16u) {
+                match self.bits(1u) {
+                    Ok(bit) => bits |= bit,
+                    Err(e) => return Err(e),
+                }
+
+                let count = code.counts[len];
+                if bits < first + count { return Ok(code.symbols[index + bits - first]); }
+
+                index += count;
+                first = (first + count) << 1u;
+                bits <<= 1u;
+            }
+
+            Err(Corrupt)
+        }
+    }
+
+    fn huffman(lengths: &[uint]) -> Result<Huffman, Error> {
+        let mut counts = vec::from_elem(16u, 0u);
+        for len in lengths.iter() { counts[*len] += 1u; }
+
+        // Refuse over-subscribed codes; incomplete ones only fail if an unused
+        // code turns up.
+        let mut left = 1i;
+        for len in range(1u, 16u) {
+            left = (left << 1) - counts[len] as int;
+            if left < 0 { return Err(Corrupt); }
+        }
+
+        let mut offsets = vec::from_elem(16u, 0u);
+        for len in range(1u, 15u) { offsets[len + 1u] = offsets[len] + counts[len]; }
+
+        let mut symbols = vec::from_elem(lengths.len(), 0u);
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0u {
+                symbols[offsets[*len]] = symbol;
+                offsets[*len] += 1u;
+            }
+        }
+
+        Ok(Huffman { counts: counts, symbols: symbols })
+    }
+
+    // Running out of input between blocks ends the stream too, since that is
+    // how a sync flushed message ends.
+    pub fn inflate(bytes: &[u8], max_size: uint) -> Result<~[u8], Error> {
+        let mut input = Input { bytes: bytes, pos: 0u, bitbuf: 0u, bitcnt: 0u };
+        let mut out = ~[];
+
+        loop {
+            if input.pos >= bytes.len() { return Ok(out); }
+
+            let last = match input.bits(1u) {
+                Ok(last) => last,
+                Err(e) => return Err(e),
+            };
+
+            let result = match input.bits(2u) {
+                Ok(0u) => stored_block(&mut input, &mut out, max_size),
+                Ok(1u) => fixed_block(&mut input, &mut out, max_size),
+                Ok(2u) => dynamic_block(&mut input, &mut out, max_size),
+                Ok(_) => Err(Corrupt),
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) if last == 1u => return Ok(out),
+                Ok(()) => { },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn stored_block(input: &mut Input, out: &mut ~[u8],
+                      max_size: uint) -> Result<(), Error> {
+        // A stored block starts on a byte boundary.
+        input.bitbuf = 0u;
+        input.bitcnt = 0u;
+
+        let bytes = input.bytes;
+        let pos = input.pos;
+        if bytes.len() - pos < 4u { return Err(Corrupt); }
+
+        let len = bytes[pos] as uint | (bytes[pos + 1u] as uint << 8u);
+        let nlen = bytes[pos + 2u] as uint | (bytes[pos + 3u] as uint << 8u);
+        if len != (!nlen & 0xffffu) || bytes.len() - pos - 4u < len {
+            return Err(Corrupt);
+        }
+        if len > max_size - out.len() { return Err(TooLarge); }
+
+        out.push_all(bytes.slice(pos + 4u, pos + 4u + len));
+        input.pos = pos + 4u + len;
+        Ok(())
+    }
+
+    fn fixed_block(input: &mut Input, out: &mut ~[u8],
+                     max_size: uint) -> Result<(), Error> {
+        let mut lengths = vec::from_elem(288u, 8u);
+        for i in range(144u, 256u) { lengths[i] = 9u; }
+        for i in range(256u, 280u) { lengths[i] = 7u; }
+
+        match (huffman(lengths), huffman(vec::from_elem(30u, 5u))) {
+            (Ok(lencode), Ok(distcode)) =>
+                codes(input, out, &lencode, &distcode, max_size),
+            _ => Err(Corrupt),
+        }
+    }
+
+    fn dynamic_block(input: &mut Input, out: &mut ~[u8],
+                       max_size: uint) -> Result<(), Error> {
+        let (nlen, ndist, ncode) = match (input.bits(5u), input.bits(5u), input.bits(4u)) {
+            (Ok(nlen), Ok(ndist), Ok(ncode)) => (nlen + 257u, ndist + 1u, ncode + 4u),
+            _ => return Err(Corrupt),
+        };
+        if nlen > 286u || ndist > 30u { return Err(Corrupt); }
+
+        let mut code_lengths = vec::from_elem(19u, 0u);
+        for i in range(0u, ncode) {
+            match input.bits(3u) {
+                Ok(len) => code_lengths[CODE_ORDER[i]] = len,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let lencode = match huffman(code_lengths) {
+            Ok(code) => code,
+            Err(e) => return Err(e),
+        };
+
+        let mut lengths = ~[];
+        while lengths.len() < nlen + ndist {
+            let symbol = match input.decode(&lencode) {
+                Ok(symbol) => symbol,
+                Err(e) => return Err(e),
+            };
+
+            if symbol < 16u {
+                lengths.push(symbol);
+                loop;
+            }
+
+            let (len, extra, base) = match symbol {
+                16u if !lengths.is_empty() => (lengths[lengths.len() - 1u], 2u, 3u),
+                17u => (0u, 3u, 3u),
+                18u => (0u, 7u, 11u),
+                _ => return Err(Corrupt),
+            };
+
+            let repeat = match input.bits(extra) {
+                Ok(repeat) => base + repeat,
+                Err(e) => return Err(e),
+            };
+            if lengths.len() + repeat > nlen + ndist { return Err(Corrupt); }
+
+            for _ in range(0u, repeat) { lengths.push(len); }
+        }
+
+        // Without an end of block code the block could never finish.
+        if lengths[256u] == 0u { return Err(Corrupt); }
+
+        match (huffman(lengths.slice(0u, nlen)), huffman(lengths.slice(nlen, nlen + ndist))) {
+            (Ok(lencode), Ok(distcode)) =>
+                codes(input, out, &lencode, &distcode, max_size),
+            _ => Err(Corrupt),
+        }
+    }
+
+    fn codes(input: &mut Input, out: &mut ~[u8], lencode: &Huffman,
+             distcode: &Huffman, max_size: uint) -> Result<(), Error> {
+        loop {
+            let symbol = match input.decode(lencode) {
+                Ok(symbol) => symbol,
+                Err(e) => return Err(e),
+            };
+
+            if symbol < 256u {
+                if out.len() >= max_size { return Err(TooLarge); }
+                out.push(symbol as u8);
+                loop;
+            }
+
+            if symbol == 256u { return Ok(()); }
+
+            let symbol = symbol - 257u;
+            if symbol >= 29u { return Err(Corrupt); }
+
+            let len = match input.bits(LENGTH_EXTRA[symbol]) {
+                Ok(extra) => LENGTH_BASE[symbol] + extra,
+                Err(e) => return Err(e),
+            };
+
+            let symbol = match input.decode(distcode) {
+                Ok(symbol) if symbol < 30u => symbol,
+                Ok(_) => return Err(Corrupt),
+                Err(e) => return Err(e),
+            };
+
+            let dist = match input.bits(DIST_EXTRA[symbol]) {
+                Ok(extra) => DIST_BASE[symbol] + extra,
+                Err(e) => return Err(e),
+            };
+
+            if dist > out.len() { return Err(Corrupt); }
+            if len > max_size - out.len() { return Err(TooLarge); }
+
+            for _ in range(0u, len) {
+                let byte = out[out.len() - dist];
+                out.push(byte);
+            }
+        }
+    }
+}
+
+fn negotiate_subprotocol(offered: &[~str], supported: &[~str]) -> Option<~str> {
+    for protocol in offered.iter() {
+        if supported.contains(protocol) { return Some(protocol.clone()); }
+    }
+    None
+}
+
+#[deriving(Clone, Eq)]
+pub struct WsMessage {
+    opcode: u8,
+    payload: ~[u8],
+}
+
+pub static WS_CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub static WS_CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub static WS_CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+// The most a reassembled message may grow to, before or after inflating,
+// unless WsReassembler::set_max_message_size says otherwise.
+pub static WS_MAX_MESSAGE_SIZE: uint = 16777216u;
+
+// Carries the close code a handler should send back when it gives up on a
+// WebSocket connection.
+#[deriving(Clone, Eq)]
+pub struct WsError {
+    code: u16,
+    reason: ~str,
+}
+
+fn ws_error(code: u16, reason: &str) -> WsError {
+    WsError { code: code, reason: reason.to_owned() }
+}
+
+// Joins fragmented data frames back into whole messages. Control frames may
+// arrive in the middle of a fragmented message and are passed straight
+// through without disturbing it.
+pub struct WsReassembler {
+    priv opcode: Option<u8>,
+    priv compressed: bool,
+    priv deflate: bool,
+    priv buffer: ~[u8],
+    priv max_size: uint,
+}
+
+pub fn WsReassembler() -> WsReassembler {
+    WsReassembler {
+        opcode: None,
+        compressed: false,
+        deflate: false,
+        buffer: ~[],
+        max_size: WS_MAX_MESSAGE_SIZE,
+    }
+}
+
+impl WsReassembler {
+    // Once permessage-deflate has been negotiated, compressed messages are
+    // inflated before they are handed out.
+    pub fn enable_deflate(&mut self) {
+        self.deflate = true;
+    }
+
+    // Caps the size of a reassembled message, before and after inflating.
+    // Defaults to WS_MAX_MESSAGE_SIZE.
+    pub fn set_max_message_size(&mut self, size: uint) {
+        self.max_size = size;
+    }
+
+    fn too_big(&mut self) -> WsError {
+        self.opcode = None;
+        self.buffer = ~[];
+        ws_error(WS_CLOSE_MESSAGE_TOO_BIG, "websocket message too big")
+    }
+
+    pub fn push(&mut self, frame: WsFrame) -> Result<Option<WsMessage>, WsError> {
+        let WsFrame { fin, compressed, opcode, payload } = frame;
+
+        if compressed && (!self.deflate || opcode >= WS_CLOSE || opcode == WS_CONTINUATION) {
+            return Err(ws_error(WS_CLOSE_PROTOCOL_ERROR,
+                                "unexpected compressed websocket frame"));
+        }
+
+        if opcode >= WS_CLOSE {
+            if !fin {
+                return Err(ws_error(WS_CLOSE_PROTOCOL_ERROR,
+                                    "fragmented websocket control frame"));
+            }
+            return validate_ws_message(WsMessage { opcode: opcode, payload: payload });
+        }
+
+        match self.opcode {
+            None => {
+                if opcode == WS_CONTINUATION {
+                    return Err(ws_error(WS_CLOSE_PROTOCOL_ERROR,
+                                        "websocket continuation without a message"));
+                }
+
+                let payload = if compressed && fin {
+                    match ws_inflate(payload, self.max_size) {
+                        Ok(payload) => payload,
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    payload
+                };
+
+                if !fin && payload.len() > self.max_size { return Err(self.too_big()); }
+
+                if fin {
+                    validate_ws_message(WsMessage { opcode: opcode, payload: payload })
+                } else {
+                    self.opcode = Some(opcode);
+                    self.compressed = compressed;
+                    self.buffer = payload;
+                    Ok(None)
+                }
+            }
+            Some(message_opcode) => {
+                if opcode != WS_CONTINUATION {
+                    return Err(ws_error(WS_CLOSE_PROTOCOL_ERROR,
+                                        "websocket message interrupted by a new message"));
+                }
+
+                if payload.len() > self.max_size - self.buffer.len() {
+                    return Err(self.too_big());
+                }
+                self.buffer.push_all(payload);
+
+                if fin {
+                    self.opcode = None;
+                    let payload = util::replace(&mut self.buffer, ~[]);
+                    let payload = if self.compressed {
+                        match ws_inflate(payload, self.max_size) {
+                            Ok(payload) => payload,
+                            Err(e) => return Err(e),
+                        }
+                    } else {
+                        payload
+                    };
+                    validate_ws_message(WsMessage { opcode: message_opcode, payload: payload })
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+// Text messages and close reasons must be valid UTF-8. This is only checked
+// once a message is whole, since a fragment may end mid character.
+fn validate_ws_message(msg: WsMessage) -> Result<Option<WsMessage>, WsError> {
+    let valid = if msg.opcode == WS_TEXT {
+        str::is_utf8(msg.payload)
+    } else if msg.opcode == WS_CLOSE && msg.payload.len() > 2u {
+        str::is_utf8(msg.payload.slice_from(2u))
+    } else {
+        true
+    };
+
+    if valid {
+        Ok(Some(msg))
+    } else {
+        Err(ws_error(WS_CLOSE_INVALID_PAYLOAD, "invalid UTF-8 in websocket message"))
+    }
+}
+
+// Test builds keep a task local copy of every outgoing message so the reply
+// helpers can be checked without a running Mongrel2.
+#[cfg(test)]
+static sent_key: local_data::Key<@mut ~[~[u8]]> = &local_data::Key;
+
+#[cfg(test)]
+fn record_sent(msg: &[u8]) {
+    let sent = do local_data::get(sent_key) |sent| {
+        match sent {
+            Some(sent) => Some(*sent),
+            None => None,
+        }
+    };
+
+    match sent {
+        Some(sent) => sent.push(msg.to_owned()),
+        None => local_data::set(sent_key, @mut ~[msg.to_owned()]),
+    }
+}
+
+#[cfg(not(test))]
+fn record_sent(_msg: &[u8]) { }
+
+pub enum WebhookAlgorithm {
+    WebhookSha1,
+    WebhookSha256,
+}
+
+pub enum EtagAlgorithm {
+    // A fast FNV-1a hash, good enough to notice changes but not collision
+    // resistant, so its tags are marked weak.
+    EtagFnv1a,
+    // A SHA-1 digest of the body, which gives a strong tag.
+    EtagSha1,
+}
+
+pub fn etag(body: &[u8]) -> ~str {
+    etag_with(body, EtagSha1)
+}
+
+pub fn etag_with(body: &[u8], algo: EtagAlgorithm) -> ~str {
+    match algo {
+        EtagFnv1a => {
+            let mut hash = 0xcbf29ce484222325u64;
+            for b in body.iter() {
+                hash ^= *b as u64;
+                hash *= 0x100000001b3u64;
+            }
+
+            let mut bytes = ~[];
+            for i in range(0u, 8u) {
+                bytes.push((hash >> ((56u - i * 8u) as u64)) as u8);
+            }
+
+            fmt!("W/\"%s\"", to_hex(bytes))
+        }
+        EtagSha1 => {
+            let mut digest = Sha1::new();
+            digest.input(body);
+            fmt!("\"%s\"", digest.result_str())
+        }
+    }
+}
+
+// Decodes the claims segment of a JWT. The signature is NOT verified, so the
+// claims must not be trusted until it has been checked separately.
+pub fn jwt_claims(token: &str) -> Option<json::Json> {
+    let segments: ~[&str] = token.split_iter('.').collect();
+    if segments.len() != 3u { return None; }
+
+    let payload = match base64_decode(segments[1u]) {
+        None => return None,
+        Some(payload) => payload,
+    };
+
+    if !str::is_utf8(payload) { return None; }
+
+    match json::from_str(str::from_bytes(payload)) {
+        Ok(claims) => Some(claims),
+        Err(_) => None,
+    }
+}
+
+// A CSRF token is an HMAC of the session id, so it can be checked without
+// keeping any server side state beyond the secret.
+pub fn csrf_token(session_id: &str, secret: &[u8]) -> ~str {
+    base64_encode(hmac(&mut Sha256::new(), secret, session_id.as_bytes()), true)
+}
+
+pub fn verify_csrf(token: &str, session_id: &str, secret: &[u8]) -> bool {
+    constant_time_eq(token.as_bytes(), csrf_token(session_id, secret).as_bytes())
+}
+
+// Signs a path and its query parameters for a link that can be checked
+// later with verify_signed_url. Parameters are sorted first, so the order
+// they end up in the URL doesn't matter.
+pub fn url_signature(path: &str, params: &[(~str, ~str)], secret: &[u8]) -> ~str {
+    let mut params = params.to_owned();
+    sort::quick_sort(params, |a, b| *a <= *b);
+
+    let mut canonical = path.to_owned();
+    for (i, param) in params.iter().enumerate() {
+        let (ref key, ref value) = *param;
+        canonical.push_char(if i == 0u { '?' } else { '&' });
+        canonical.push_str(url_encode(key.as_slice()));
+        canonical.push_char('=');
+        canonical.push_str(url_encode(value.as_slice()));
+    }
+
+    base64_encode(hmac(&mut Sha256::new(), secret, canonical.as_bytes()), true)
+}
+
+// Checks the signature carried in the `sig_param` query parameter against
+// the rest of the request's path and query.
+pub fn verify_signed_url(req: &Request, secret: &[u8], sig_param: &str) -> bool {
+    let mut signature = None;
+    let mut params = ~[];
+
+    for pair in req.query_pairs().consume_iter() {
+        let (key, value) = pair;
+        if key.as_slice() == sig_param {
+            if signature.is_some() { return false; }
+            signature = Some(value);
+        } else {
+            params.push((key, value));
+        }
+    }
+
+    let signature = match signature {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    constant_time_eq(signature.as_bytes(),
+                     url_signature(req.path_only(), params, secret).as_bytes())
+}
+
+// HMAC (RFC 2104) for digests with a 64 byte block, which covers SHA-1 and
+// SHA-256.
+fn hmac<D: Digest>(digest: &mut D, key: &[u8], data: &[u8]) -> ~[u8] {
+    let block_size = 64u;
+
+    let mut key = if key.len() > block_size {
+        digest.reset();
+        digest.input(key);
+        digest_result(digest)
+    } else {
+        key.to_owned()
+    };
+    key.grow(block_size - key.len(), &0u8);
+
+    let inner_pad = key.map(|b| *b ^ 0x36u8);
+    let outer_pad = key.map(|b| *b ^ 0x5cu8);
+
+    digest.reset();
+    digest.input(inner_pad);
+    digest.input(data);
+    let inner = digest_result(digest);
+
+    digest.reset();
+    digest.input(outer_pad);
+    digest.input(inner);
+    digest_result(digest)
+}
+
+fn digest_result<D: Digest>(digest: &mut D) -> ~[u8] {
+    let len = digest.output_bits() / 8u;
+    let mut out = vec::from_elem(len, 0u8);
+    digest.result(out.mut_slice(0u, len));
+    out
+}
+
+// Compares without returning early, so the time taken doesn't reveal how
+// much of a secret value matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() { return false; }
+
+    let mut diff = 0u8;
+    for i in range(0u, a.len()) {
+        diff |= a[i] ^ b[i];
+    }
+    diff == 0u8
+}
+
+// Generates a URL safe token from `len` bytes of the system's secure random
+// source, suitable for use as a session id.
+pub fn new_session_token(len: uint) -> ~str {
+    base64_encode(random_bytes(len), true)
+}
+
+// A random multipart boundary. 128 random bits make it vanishingly unlikely
+// to turn up in a body, and it only uses characters that need no quoting in
+// a Content-Type parameter.
+pub fn generate_boundary() -> ~str {
+    ~"mongrel2-" + to_hex(random_bytes(16u))
+}
+
+fn random_bytes(len: uint) -> ~[u8] {
+    match io::file_reader(&Path("/dev/urandom")) {
+        Ok(rdr) => rdr.read_bytes(len),
+        Err(e) => fail!(fmt!("cannot read random bytes: %s", e)),
+    }
+}
+
+// Encodes with the standard alphabet and padding, or with the URL safe
+// alphabet and no padding.
+fn base64_encode(bytes: &[u8], url_safe: bool) -> ~str {
+    let table = if url_safe {
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_"
+    } else {
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/"
+    };
+
+    let mut s = ~"";
+    let mut i = 0u;
+
+    while i < bytes.len() {
+        let n = uint::min(3u, bytes.len() - i);
+        let mut acc = 0u;
+        for j in range(0u, 3u) {
+            acc <<= 8u;
+            if j < n { acc |= bytes[i + j] as uint; }
+        }
+
+        for j in range(0u, n + 1u) {
+            s.push_char(table[(acc >> (18u - j * 6u)) & 63u] as char);
+        }
+        if !url_safe {
+            for _ in range(n, 3u) { s.push_char('='); }
+        }
+
+        i += n;
+    }
+
+    s
+}
+
+// Decodes both the standard and URL safe base64 alphabets, with or without
+// padding.
+fn base64_decode(s: &str) -> Option<~[u8]> {
+    let mut out = ~[];
+    let mut acc = 0u;
+    let mut bits = 0u;
+
+    for ch in s.trim_right_chars(&'=').iter() {
+        let v = match ch {
+            'A'..'Z' => ch as uint - 'A' as uint,
+            'a'..'z' => ch as uint - 'a' as uint + 26u,
+            '0'..'9' => ch as uint - '0' as uint + 52u,
+            '+' | '-' => 62u,
+            '/' | '_' => 63u,
+            _ => return None,
+        };
+
+        acc = (acc << 6u) | v;
+        bits += 6u;
+
+        if bits >= 8u {
+            bits -= 8u;
+            out.push((acc >> bits) as u8);
+            acc &= (1u << bits) - 1u;
+        }
+    }
+
+    // A single leftover character can't encode a whole byte.
+    if bits >= 6u { return None; }
+
+    Some(out)
+}
+
+// Formats seconds since the epoch as an RFC 1123 date.
+pub fn http_date(timestamp: i64) -> ~str {
+    time::at_utc(time::Timespec::new(timestamp, 0i32))
+        .strftime("%a, %d %b %Y %H:%M:%S GMT")
+}
+
+// Parses an RFC 1123 date, such as "Sun, 06 Nov 1994 08:49:37 GMT", into
+// seconds since the epoch.
+pub fn parse_http_date(date: &str) -> Option<i64> {
+    match time::strptime(date.trim(), "%a, %d %b %Y %H:%M:%S GMT") {
+        Ok(tm) => Some(tm.to_timespec().sec),
+        Err(_) => None,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> ~str {
+    let mut s = ~"";
+    for b in bytes.iter() {
+        s.push_str(fmt!("%02x", *b as uint));
+    }
+    s
+}
+
+// TODO: there is no `as_bytes' for ~str that will return ~[u8].
+fn str_as_bytes(s: ~str) -> ~[u8] {
+    let s = s.clone();
+    let mut buf: ~[u8] = unsafe { cast::transmute(s) };
+    buf.pop();
+    buf
+}
+
+pub type Headers = HashMap<~str, ~[~str]>;
+
+pub fn Headers() -> Headers {
+    HashMap::new()
+}
+
+// Replaces any existing values of a response header.
+fn set_header(headers: &mut Headers, name: &str, value: ~str) {
+    headers.insert(name.to_owned(), ~[value]);
+}
+
+#[deriving(Clone, Eq)]
+pub struct CacheControl {
+    max_age: Option<uint>,
+    no_cache: bool,
+    no_store: bool,
+    public: bool,
+    private: bool,
+    must_revalidate: bool,
+}
+
+pub fn CacheControl() -> CacheControl {
+    CacheControl {
+        max_age: None,
+        no_cache: false,
+        no_store: false,
+        public: false,
+        private: false,
+        must_revalidate: false,
+    }
+}
+
+impl CacheControl {
+    pub fn max_age(self, secs: uint) -> CacheControl {
+        let mut cc = self;
+        cc.max_age = Some(secs);
+        cc
+    }
+
+    pub fn no_cache(self) -> CacheControl {
+        let mut cc = self;
+        cc.no_cache = true;
+        cc
+    }
+
+    pub fn no_store(self) -> CacheControl {
+        let mut cc = self;
+        cc.no_store = true;
+        cc
+    }
+
+    pub fn public(self) -> CacheControl {
+        let mut cc = self;
+        cc.public = true;
+        cc.private = false;
+        cc
+    }
+
+    pub fn private(self) -> CacheControl {
+        let mut cc = self;
+        cc.private = true;
+        cc.public = false;
+        cc
+    }
+
+    pub fn must_revalidate(self) -> CacheControl {
+        let mut cc = self;
+        cc.must_revalidate = true;
+        cc
+    }
+
+    pub fn to_header_value(&self) -> ~str {
+        let mut directives = ~[];
+
+        if self.public { directives.push(~"public"); }
+        if self.private { directives.push(~"private"); }
+        if self.no_cache { directives.push(~"no-cache"); }
+        if self.no_store { directives.push(~"no-store"); }
+        match self.max_age {
+            None => { },
+            Some(secs) => directives.push(fmt!("max-age=%u", secs)),
+        }
+        if self.must_revalidate { directives.push(~"must-revalidate"); }
+
+        directives.connect(", ")
+    }
+}
+
+pub fn with_cache_control(headers: &mut Headers, cc: &CacheControl) {
+    set_header(headers, "Cache-Control", cc.to_header_value());
+}
+
+pub fn with_content_language(headers: &mut Headers, langs: &[~str]) {
+    set_header(headers, "Content-Language", langs.connect(", "));
+}
+
+pub fn with_retry_after(headers: &mut Headers, secs: uint) {
+    set_header(headers, "Retry-After", uint::to_str(secs));
+}
+
+pub fn with_retry_after_date(headers: &mut Headers, timestamp: i64) {
+    set_header(headers, "Retry-After", http_date(timestamp));
+}
+
+// A cookie to send to the client with Set-Cookie.
+#[deriving(Clone, Eq)]
+pub struct Cookie {
+    name: ~str,
+    value: ~str,
+    path: Option<~str>,
+    domain: Option<~str>,
+    max_age: Option<uint>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<~str>,
+}
+
+pub fn Cookie(name: &str, value: &str) -> Cookie {
+    Cookie {
+        name: name.to_owned(),
+        value: value.to_owned(),
+        path: None,
+        domain: None,
+        max_age: None,
+        secure: false,
+        http_only: false,
+        same_site: None,
+    }
+}
+
+impl Cookie {
+    pub fn path(self, path: &str) -> Cookie {
+        let mut cookie = self;
+        cookie.path = Some(path.to_owned());
+        cookie
+    }
+
+    pub fn domain(self, domain: &str) -> Cookie {
+        let mut cookie = self;
+        cookie.domain = Some(domain.to_owned());
+        cookie
+    }
+
+    pub fn max_age(self, secs: uint) -> Cookie {
+        let mut cookie = self;
+        cookie.max_age = Some(secs);
+        cookie
+    }
+
+    pub fn secure(self) -> Cookie {
+        let mut cookie = self;
+        cookie.secure = true;
+        cookie
+    }
+
+    pub fn http_only(self) -> Cookie {
+        let mut cookie = self;
+        cookie.http_only = true;
+        cookie
+    }
+
+    // One of "Strict", "Lax" or "None".
+    pub fn same_site(self, policy: &str) -> Cookie {
+        let mut cookie = self;
+        cookie.same_site = Some(policy.to_owned());
+        cookie
+    }
+
+    // Refuses anything that could end the cookie early or smuggle in
+    // attributes or headers of its own, rather than escaping it.
+    pub fn to_header_value(&self) -> Result<~str, ~str> {
+        if self.name.is_empty() || !self.name.iter().all(|c| cookie_name_char(c)) {
+            return Err(fmt!("invalid cookie name: %s", self.name));
+        }
+        if !self.value.iter().all(|c| cookie_value_char(c)) {
+            return Err(fmt!("invalid value for cookie %s", self.name));
+        }
+        for attr in [&self.path, &self.domain].iter() {
+            match **attr {
+                Some(ref attr) if !attr.iter().all(|c| cookie_attr_char(c)) =>
+                    return Err(fmt!("invalid attribute for cookie %s", self.name)),
+                _ => { },
+            }
+        }
+        match self.same_site {
+            Some(ref policy) if *policy != ~"Strict" && *policy != ~"Lax" &&
+                                *policy != ~"None" =>
+                return Err(fmt!("invalid SameSite policy: %s", *policy)),
+            _ => { },
+        }
+
+        let mut parts = ~[fmt!("%s=%s", self.name, self.value)];
+
+        match self.path {
+            Some(ref path) => parts.push(fmt!("Path=%s", *path)),
+            None => { },
+        }
+        match self.domain {
+            Some(ref domain) => parts.push(fmt!("Domain=%s", *domain)),
+            None => { },
+        }
+        match self.max_age {
+            Some(secs) => parts.push(fmt!("Max-Age=%u", secs)),
+            None => { },
+        }
+        if self.secure { parts.push(~"Secure"); }
+        if self.http_only { parts.push(~"HttpOnly"); }
+        match self.same_site {
+            Some(ref policy) => parts.push(fmt!("SameSite=%s", *policy)),
+            None => { },
+        }
+
+        Ok(parts.connect("; "))
+    }
+}
+
+// A token, as in RFC 6265.
+fn cookie_name_char(c: char) -> bool {
+    c > ' ' && c < '\x7f' && !"()<>@,;:\\\"/[]?={}".contains_char(c)
+}
+
+fn cookie_value_char(c: char) -> bool {
+    c > ' ' && c < '\x7f' && c != '"' && c != ',' && c != ';' && c != '\\'
+}
+
+fn cookie_attr_char(c: char) -> bool {
+    header_value_char(c) && c != ';' && c != ','
+}
+
+fn header_value_char(c: char) -> bool {
+    c >= ' ' && c < '\x7f'
+}
+
+// Adds a Set-Cookie header for each cookie, keeping any already set, since
+// every cookie needs a header of its own. Nothing is added if any cookie is
+// invalid.
+pub fn with_cookies(headers: &mut Headers, cookies: &[Cookie]) -> Result<(), ~str> {
+    let mut values = ~[];
+    for cookie in cookies.iter() {
+        match cookie.to_header_value() {
+            Ok(value) => values.push(value),
+            Err(e) => return Err(e),
+        }
+    }
+
+    headers.mangle(~"Set-Cookie", values,
+        |_, values| values,
+        |_, existing, values| existing.push_all_move(values));
+    Ok(())
+}
+
+// How long, in seconds, a response served from a cache has been stored.
+pub fn with_age(headers: &mut Headers, age_secs: uint) {
+    set_header(headers, "Age", uint::to_str(age_secs));
+}
+
+pub fn with_cache_hit(headers: &mut Headers) {
+    set_header(headers, "X-Cache", ~"HIT");
+}
+
+pub fn with_cache_miss(headers: &mut Headers) {
+    set_header(headers, "X-Cache", ~"MISS");
+}
+
+// Asks the browser to drop stored data for the site, e.g. on logout. Each
+// directive, like "cookies" or "storage", goes out as a quoted string, so
+// only tokens are accepted and nothing is set otherwise.
+pub fn with_clear_site_data(headers: &mut Headers, directives: &[~str]) -> Result<(), ~str> {
+    for directive in directives.iter() {
+        if directive.is_empty() || !directive.iter().all(|c| cookie_name_char(c)) {
+            return Err(fmt!("invalid Clear-Site-Data directive: %s", *directive));
+        }
+    }
+
+    let quoted = directives.map(|directive| fmt!("\"%s\"", *directive));
+    set_header(headers, "Clear-Site-Data", quoted.connect(", "));
+    Ok(())
+}
+
+static HOP_HEADERS: &'static [&'static str] = &[
+    "connection", "keep-alive", "proxy-authenticate", "proxy-authorization",
+    "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade",
+];
+
+fn strip_hop_headers(response: &[u8]) -> Result<~[u8], ~str> {
+    let head_end = match find_bytes(response, "\r\n\r\n".as_bytes()) {
+        Some(i) => i,
+        None => return Err(~"truncated upstream response head"),
+    };
+    let body = response.slice_from(head_end + 4u);
+
+    let mut lines = ~[];
+    let mut rest = response.slice_to(head_end + 2u);
+    loop {
+        match split_line(rest) {
+            Some((line, next)) => {
+                lines.push(line);
+                rest = next;
+            }
+            None => break,
+        }
+    }
+    if lines.is_empty() { return Err(~"missing upstream status line"); }
+
+    let mut fields = ~[];
+    for line in lines.slice_from(1u).iter() {
+        let colon = match line.position_elem(&(':' as u8)) {
+            Some(colon) => colon,
+            None => return Err(~"malformed upstream header"),
+        };
+        let name = match view_str(line.slice_to(colon)) {
+            Ok(name) => name.trim().to_ascii_lower(),
+            Err(e) => return Err(e),
+        };
+        let value = match view_str(line.slice_from(colon + 1u)) {
+            Ok(value) => value.trim().to_owned(),
+            Err(_) => ~"",
+        };
+        fields.push((name, value, *line));
+    }
+
+    let mut hop = HOP_HEADERS.map(|name| (*name).to_owned());
+    let mut chunked = false;
+    for field in fields.iter() {
+        let (ref name, ref value, _) = *field;
+        if *name == ~"connection" {
+            for token in split_list(*value).iter() { hop.push(token.to_ascii_lower()); }
+        } else if *name == ~"transfer-encoding" {
+            chunked = split_list(*value).iter().any(|coding| {
+                coding.eq_ignore_ascii_case("chunked")
+            });
+        }
+    }
+
+    let mut out = lines[0u].to_owned();
+    out.push_all("\r\n".as_bytes());
+    for field in fields.iter() {
+        let (ref name, _, line) = *field;
+        if hop.contains(name) { loop; }
+        if chunked && *name == ~"content-length" { loop; }
+        out.push_all(line);
+        out.push_all("\r\n".as_bytes());
+    }
+
+    if chunked {
+        let body = match dechunk(body, None) {
+            Ok(body) => body,
+            Err(e) => return Err(e),
+        };
+        out.push_all(str_as_bytes(fmt!("Content-Length: %u\r\n\r\n", body.len())));
+        out.push_all(body);
+    } else {
+        out.push_all("\r\n".as_bytes());
+        out.push_all(body);
+    }
+
+    Ok(out)
+}
+
+// Tells the client which of its Prefer preferences were honoured. An empty
+// value is sent as a bare token.
+pub fn with_preference_applied(headers: &mut Headers, prefs: &[(~str, ~str)]) {
+    let applied = do prefs.map |pref| {
+        let (ref name, ref value) = *pref;
+        if value.is_empty() { name.clone() } else { fmt!("%s=%s", *name, *value) }
+    };
+
+    set_header(headers, "Preference-Applied", applied.connect(", "));
+}
+
+// Adds this proxy to the end of the Via chain, as in "1.0 upstream, 1.1
+// pseudonym". The header is looked for in any case and written back as Via.
+pub fn append_via(headers: &mut Headers, pseudonym: &str, version: (uint, uint)) {
+    let (major, minor) = version;
+
+    let mut keys = ~[];
+    for (key, _) in headers.iter() {
+        if key.eq_ignore_ascii_case("via") { keys.push(key.clone()); }
+    }
+
+    let mut hops = ~[];
+    for key in keys.iter() {
+        match headers.pop(key) {
+            Some(values) => {
+                for value in values.iter() { hops.push_all_move(split_list(*value)); }
+            }
+            None => { },
+        }
+    }
+
+    hops.push(fmt!("%u.%u %s", major, minor, pseudonym));
+    set_header(headers, "Via", hops.connect(", "));
+}
+
+fn add_vary(headers: &mut Headers, name: &str) {
+    let mut vary = ~[];
+    for (key, values) in headers.iter() {
+        if key.eq_ignore_ascii_case("vary") {
+            for value in values.iter() { vary.push_all_move(split_list(*value)); }
+        }
+    }
+    if vary.iter().any(|v| v.eq_ignore_ascii_case(name)) { return; }
+
+    vary.push(name.to_owned());
+    set_header(headers, "Vary", vary.connect(", "));
+}
+
+// Directives are rendered in the order they were first added.
+#[deriving(Clone, Eq)]
+pub struct ContentSecurityPolicy {
+    directives: ~[(~str, ~[~str])],
+}
+
+pub fn ContentSecurityPolicy() -> ContentSecurityPolicy {
+    ContentSecurityPolicy { directives: ~[] }
+}
+
+impl ContentSecurityPolicy {
+    // Adds sources to a directive such as "script-src", creating it if it
+    // isn't there yet. Keyword sources need their quotes, as in "'self'".
+    pub fn directive(self, name: &str, sources: &[&str]) -> ContentSecurityPolicy {
+        let mut csp = self;
+        let name = name.to_ascii_lower();
+
+        let position = do csp.directives.iter().position |directive| {
+            let (ref existing, _) = *directive;
+            *existing == name
+        };
+
+        match position {
+            Some(i) => {
+                match csp.directives[i] {
+                    (_, ref mut existing) => {
+                        for source in sources.iter() {
+                            let source = (*source).to_owned();
+                            if !existing.contains(&source) { existing.push(source); }
+                        }
+                    }
+                }
+            }
+            None => {
+                let sources = sources.map(|source| (*source).to_owned());
+                csp.directives.push((name, sources));
+            }
+        }
+
+        csp
+    }
+
+    pub fn to_header_value(&self) -> ~str {
+        let directives = do self.directives.map |directive| {
+            let (ref name, ref sources) = *directive;
+            if sources.is_empty() {
+                name.clone()
+            } else {
+                fmt!("%s %s", *name, sources.connect(" "))
+            }
+        };
+
+        directives.connect("; ")
+    }
+}
+
+pub fn with_content_security_policy(headers: &mut Headers, csp: &ContentSecurityPolicy) {
+    set_header(headers, "Content-Security-Policy", csp.to_header_value());
+}
+
+struct TimingMetric {
+    name: ~str,
+    duration: float,
+    description: Option<~str>,
+}
+
+// Collects named timings, in milliseconds, for the Server-Timing header.
+pub struct ServerTiming {
+    priv metrics: ~[TimingMetric],
+}
+
+pub fn ServerTiming() -> ServerTiming {
+    ServerTiming { metrics: ~[] }
+}
+
+impl ServerTiming {
+    pub fn add(&mut self, name: &str, duration_ms: float) {
+        self.metrics.push(TimingMetric {
+            name: name.to_owned(),
+            duration: duration_ms,
+            description: None,
+        });
+    }
+
+    // The description goes out as a quoted string, so quotes and
+    // backslashes are escaped, but control characters are refused.
+    pub fn add_described(&mut self, name: &str, duration_ms: float,
+                         description: &str) -> Result<(), ~str> {
+        if !description.iter().all(|c| header_value_char(c)) {
+            return Err(fmt!("invalid description for timing %s", name));
+        }
+
+        self.metrics.push(TimingMetric {
+            name: name.to_owned(),
+            duration: duration_ms,
+            description: Some(description.to_owned()),
+        });
+        Ok(())
+    }
+
+    // Runs `f` and records how long it took under `name`.
+    pub fn time<T>(&mut self, name: &str, f: &fn() -> T) -> T {
+        let start = time::precise_time_ns();
+        let result = f();
+        let elapsed = time::precise_time_ns() - start;
+        self.add(name, elapsed as float / 1000000f);
+        result
+    }
+
+    pub fn to_header_value(&self) -> ~str {
+        let metrics = do self.metrics.map |metric| {
+            let mut value = metric.name.clone();
+            match metric.description {
+                None => { },
+                Some(ref desc) => value.push_str(";desc=" + quoted_string(*desc)),
+            }
+            value.push_str(";dur=" + metric.duration.to_str());
+            value
+        };
+
+        metrics.connect(", ")
+    }
+}
+
+// Quotes `s` as an RFC 7230 quoted-string. Callers have already refused
+// control characters, which can't be quoted.
+fn quoted_string(s: &str) -> ~str {
+    let mut quoted = ~"\"";
+    for c in s.iter() {
+        if c == '"' || c == '\\' { quoted.push_char('\\'); }
+        quoted.push_char(c);
+    }
+    quoted.push_char('"');
+    quoted
+}
+
+pub fn with_server_timing(headers: &mut Headers, timing: &ServerTiming) {
+    set_header(headers, "Server-Timing", timing.to_header_value());
+}
+
+#[deriving(Clone)]
+pub struct Response {
+    code: uint,
+    status: ~str,
+    headers: Headers,
+    body: ~[u8],
+}
+
+impl Response {
+    pub fn to_bytes(&self) -> ~[u8] {
+        let mut rep = http_head("HTTP/1.1", self.code, self.status, &self.headers,
+                                Some(self.body.len()), PreserveCase, false);
+        rep.push_all(self.body);
+        rep
+    }
+}
+
+// The number of bytes `resp.to_bytes()` would produce, worked out without
+// building them.
+pub fn response_size(resp: &Response) -> uint {
+    let mut size = "HTTP/1.1 ".len() + uint::to_str(resp.code).len() + 1u +
+        resp.status.len() + 2u;
+
+    size += "Content-Length: ".len() + uint::to_str(resp.body.len()).len() + 2u;
+
+    for (key, values) in resp.headers.iter() {
+        for value in values.iter() {
+            size += key.len() + 2u + value.len() + 2u;
+        }
+    }
+
+    size + 2u + resp.body.len()
+}
+
+// A fluent way to put together a `Response`, e.g.
+// `response::ok().header("X-A", "1").text("hi").status(201).build()`.
+pub mod response {
+    use super::{Headers, Response, set_header, status_reason};
+    use extra::json;
+    use extra::json::ToStr;
+
+    pub struct ResponseBuilder {
+        priv response: Response,
+    }
+
+    pub fn ok() -> ResponseBuilder {
+        status(200u)
+    }
+
+    pub fn status(code: uint) -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response {
+                code: code,
+                status: status_reason(code).to_owned(),
+                headers: Headers(),
+                body: ~[],
+            }
+        }
+    }
+
+    impl ResponseBuilder {
+        pub fn status(self, code: uint) -> ResponseBuilder {
+            let mut builder = self;
+            builder.response.code = code;
+            builder.response.status = status_reason(code).to_owned();
+            builder
+        }
+
+        pub fn header(self, name: &str, value: &str) -> ResponseBuilder {
+            let mut builder = self;
+            builder.response.headers.mangle(name.to_owned(), value,
+                |_, value| ~[value.to_owned()],
+                |_, values, value| values.push(value.to_owned()));
+            builder
+        }
+
+        pub fn body(self, body: &[u8]) -> ResponseBuilder {
+            let mut builder = self;
+            builder.response.body = body.to_owned();
+            builder
+        }
+
+        pub fn text(self, body: &str) -> ResponseBuilder {
+            let mut builder = self.body(body.as_bytes());
+            set_header(&mut builder.response.headers, "Content-Type",
+                       ~"text/plain; charset=utf-8");
+            builder
+        }
+
+        pub fn json(self, value: &json::Json) -> ResponseBuilder {
+            let mut builder = self.body(value.to_str().as_bytes());
+            set_header(&mut builder.response.headers, "Content-Type",
+                       ~"application/json");
+            builder
+        }
+
+        pub fn build(self) -> Response {
+            self.response
+        }
+    }
+}
+
+#[deriving(Clone)]
+pub struct Request {
+    uuid: ~str,
+    id: ~str,
+    path: ~str,
+    headers: Headers,
+    body: ~[u8],
+    json_body: Option<~json::Object>,
+    sequence: Option<uint>,
+    wire_size: uint,
+    raw_headers: Option<~[u8]>,
+}
+
+#[deriving(Clone, Eq)]
+pub struct RequestCookie {
+    name: ~str,
+    value: ~str,
+    path: Option<~str>,
+    domain: Option<~str>,
+}
+
+impl Request {
+    // Mongrel2 announces closed connections with a JSON message on its "@*"
+    // control path. Such a request should be answered with close_connection,
+    // if at all, rather than a normal reply.
+    pub fn is_disconnect(&self) -> bool {
+        if self.json_type() == Some(~"disconnect") { return true; }
+
+        self.path == ~"@*" && !self.is_flow_control() &&
+            self.header_first("METHOD") == Some(~"JSON")
+    }
+
+    // Mongrel2 hands on each WebSocket frame from the client as the body of
+    // a request with METHOD set to WEBSOCKET. Returns the frame as it came
+    // off the wire, ready for decode_ws_frame.
+    pub fn ws_payload(&self) -> Option<~[u8]> {
+        match self.header_first("METHOD") {
+            Some(ref method) if *method == ~"WEBSOCKET" => Some(self.body.clone()),
+            _ => None,
+        }
+    }
+
+    // Frees the body once a handler is done with it, keeping the rest of
+    // the request around.
+    pub fn release_body(&mut self) {
+        self.body = ~[];
+    }
+
+    // Just enough of the request to reply to it with, once the request
+    // itself has been handed off.
+    fn reply_target(&self) -> Request {
+        let mut headers = Headers();
+        for (key, values) in self.headers.iter() {
+            if key.eq_ignore_ascii_case("VERSION") {
+                headers.insert(key.clone(), values.clone());
+            }
+        }
+
+        Request {
+            uuid: self.uuid.clone(),
+            id: self.id.clone(),
+            path: self.path.clone(),
+            headers: headers,
+            body: ~[],
+            json_body: None,
+            sequence: self.sequence,
+            wire_size: self.wire_size,
+            raw_headers: None,
+        }
+    }
+
+    // Mongrel2 paces streamed downloads by sending the handler JSON
+    // `credits` messages that grant it more bytes to send.
+    pub fn is_flow_control(&self) -> bool {
+        self.json_type() == Some(~"credits")
+    }
+
+    pub fn credits(&self) -> Option<uint> {
+        if !self.is_flow_control() { return None; }
+
+        match self.json_body {
+            None => None,
+            Some(ref map) => {
+                match map.find(&~"credits") {
+                    Some(&json::Number(n)) if n >= 0f => Some(n as uint),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    fn json_type(&self) -> Option<~str> {
+        match self.json_body {
+            None => None,
+            Some(ref map) => {
+                match map.find(&~"type") {
+                    Some(&json::String(ref typ)) => Some(typ.clone()),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    pub fn should_close(&self) -> bool {
+        match self.header_list("connection") {
+            None => { },
+            Some(conn) => {
+                if conn.iter().any(|token| token.eq_ignore_ascii_case("close")) {
+                    return true;
+                }
+            }
+        }
+
+        match self.header_first("VERSION") {
+            None => false,
+            Some(version) => version == ~"HTTP/1.0",
+        }
+    }
+
+    // Header names keep whatever case they arrived in, so lookups ignore
+    // case. Values are gathered from every key that matches, taking the
+    // keys in sorted order so the result doesn't depend on hash order.
+    pub fn header(&self, name: &str) -> Option<~[~str]> {
+        let mut keys = ~[];
+        for (key, _) in self.headers.iter() {
+            if key.eq_ignore_ascii_case(name) { keys.push(key.clone()); }
+        }
+        if keys.is_empty() { return None; }
+
+        sort::quick_sort(keys, |a, b| *a <= *b);
+
+        let mut found = ~[];
+        for key in keys.iter() {
+            found.push_all(*self.headers.get(key));
+        }
+        Some(found)
+    }
+
+    // Every header whose name starts with `prefix`, ignoring case, sorted by
+    // name. Handy for families of headers like X-Feature-*.
+    pub fn headers_with_prefix(&self, prefix: &str) -> ~[(~str, ~[~str])] {
+        let prefix = prefix.to_ascii_lower();
+
+        let mut found = ~[];
+        for (key, values) in self.headers.iter() {
+            if key.to_ascii_lower().starts_with(prefix) {
+                found.push((key.clone(), values.clone()));
+            }
+        }
+
+        do sort::quick_sort(found) |a, b| {
+            let (ref a, _) = *a;
+            let (ref b, _) = *b;
+            a.to_ascii_lower() <= b.to_ascii_lower()
+        }
+        found
+    }
+
+    pub fn header_first(&self, name: &str) -> Option<~str> {
+        match self.header(name) {
+            Some(values) if values.len() > 0u => Some(values[0u].clone()),
+            _ => None,
+        }
+    }
+
+    pub fn http_version(&self) -> ~str {
+        match self.header_first("VERSION") {
+            Some(version) => version,
+            None => ~"HTTP/1.1",
+        }
+    }
+
+    // Answer HTTP/1.0 clients in kind, everyone else gets HTTP/1.1.
+    fn response_version(&self) -> &'static str {
+        if self.http_version() == ~"HTTP/1.0" { "HTTP/1.0" } else { "HTTP/1.1" }
+    }
+
+    // The number of body bytes actually received, after any transfer coding
+    // has been removed, unlike the client supplied Content-Length.
+    pub fn effective_length(&self) -> uint {
+        self.body.len()
+    }
+
+    // The size of the whole message as Mongrel2 sent it, for bandwidth
+    // accounting.
+    pub fn wire_size(&self) -> uint {
+        self.wire_size
+    }
+
+    // The header netstring exactly as it appeared in the message, for
+    // signature schemes that cover the raw bytes. `None` for a request
+    // that wasn't parsed from a message.
+    pub fn raw_headers<'a>(&'a self) -> Option<&'a [u8]> {
+        match self.raw_headers {
+            Some(ref raw) => Some(raw.as_slice()),
+            None => None,
+        }
+    }
+
+    pub fn has_conflicting_content_length(&self) -> bool {
+        match self.header_list("content-length") {
+            None => false,
+            Some(lengths) => lengths.iter().any(|len| *len != lengths[0u]),
+        }
+    }
+
+    pub fn if_match(&self) -> Option<~[~str]> {
+        self.header_list("if-match")
+    }
+
+    pub fn check_if_match(&self, current_etag: &str) -> bool {
+        let etags = match self.if_match() {
+            None => return true,
+            Some(etags) => etags,
+        };
+
+        // If-Match uses the strong comparison, so weak tags never match.
+        if current_etag.starts_with("W/") { return false; }
+
+        do etags.iter().any |etag| {
+            *etag == ~"*" || (!etag.starts_with("W/") && current_etag == *etag)
+        }
+    }
+
+    pub fn if_unmodified_since(&self) -> Option<i64> {
+        match self.header_first("if-unmodified-since") {
+            None => None,
+            Some(date) => parse_http_date(date),
+        }
+    }
+
+    // Returns false when the resource changed after the client's
+    // If-Unmodified-Since date, in which case a write should be refused.
+    pub fn check_if_unmodified(&self, last_modified: i64) -> bool {
+        match self.if_unmodified_since() {
+            None => true,
+            Some(since) => last_modified <= since,
+        }
+    }
+
+    // The Host header lowercased, without its port or a trailing dot.
+    pub fn host(&self) -> Option<~str> {
+        let host = match self.header_first("host") {
+            Some(host) => host.trim().to_ascii_lower(),
+            None => return None,
+        };
+
+        let end = if host.starts_with("[") {
+            match host.find(']') {
+                Some(i) => i + 1u,
+                None => return None,
+            }
+        } else {
+            match host.find(':') {
+                Some(i) => i,
+                None => host.len(),
+            }
+        };
+
+        let name = host.slice_to(end).trim_right_chars(&'.');
+        if name.is_empty() { None } else { Some(name.to_owned()) }
+    }
+
+    pub fn host_allowed(&self, allowlist: &[~str]) -> bool {
+        match self.host() {
+            Some(host) => allowlist.iter().any(|allowed| host.eq_ignore_ascii_case(*allowed)),
+            None => false,
+        }
+    }
+
+    // The number of further hops a TRACE or OPTIONS request may be
+    // forwarded. Anything but a non-negative integer counts as missing.
+    pub fn max_forwards(&self) -> Option<int> {
+        match self.header_first("max-forwards") {
+            Some(value) => {
+                let value = value.trim();
+                if value.is_empty() || !value.iter().all(|c| c.is_digit()) { return None; }
+                int::from_str(value)
+            }
+            None => None,
+        }
+    }
+
+    // Checks an HMAC of the body against the hex signature in `sig_header`,
+    // which may carry an "sha256=" style prefix naming the algorithm.
+    pub fn verify_webhook(&self,
+                          secret: &[u8],
+                          sig_header: &str,
+                          algo: WebhookAlgorithm) -> bool {
+        let signature = match self.header_first(sig_header) {
+            Some(signature) => signature.trim().to_ascii_lower(),
+            None => return false,
+        };
+
+        let (prefix, expected) = match algo {
+            WebhookSha1 => ("sha1=", hmac(&mut Sha1::new(), secret, self.body)),
+            WebhookSha256 => ("sha256=", hmac(&mut Sha256::new(), secret, self.body)),
+        };
+
+        let signature = if signature.starts_with(prefix) {
+            signature.slice_from(prefix.len())
+        } else {
+            signature.as_slice()
+        };
+
+        constant_time_eq(signature.as_bytes(), to_hex(expected).as_bytes())
+    }
+
+    pub fn bearer_token(&self) -> Option<~str> {
+        let auth = match self.header_first("authorization") {
+            None => return None,
+            Some(auth) => auth,
+        };
+
+        let auth = auth.trim();
+        if auth.len() <= 7u || !starts_with_ignore_case(auth, "bearer ") {
+            return None;
+        }
+
+        let token = auth.slice_from(7u).trim();
+        if token.is_empty() { None } else { Some(token.to_owned()) }
+    }
+
+    // Speculative loads must not have side effects such as marking items
+    // as read.
+    pub fn is_prefetch(&self) -> bool {
+        do ["sec-purpose", "purpose", "x-moz"].iter().any |name| {
+            match self.header_list(*name) {
+                None => false,
+                Some(values) => {
+                    do values.iter().any |value| {
+                        value.split_iter(';').next().map_default(false, |token| {
+                            token.trim().eq_ignore_ascii_case("prefetch")
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn fetch_site(&self) -> Option<~str> {
+        self.header_first("sec-fetch-site")
+    }
+
+    pub fn fetch_mode(&self) -> Option<~str> {
+        self.header_first("sec-fetch-mode")
+    }
+
+    pub fn fetch_dest(&self) -> Option<~str> {
+        self.header_first("sec-fetch-dest")
+    }
+
+    pub fn accepts_encoding(&self, encoding: &str) -> bool {
+        let accepted = match self.header_list("accept-encoding") {
+            Some(items) => parse_weighted(items),
+            None => return false,
+        };
+
+        let mut wildcard = None;
+        for accept in accepted.iter() {
+            let (ref name, q) = *accept;
+            if name.eq_ignore_ascii_case(encoding) { return q > 0f64; }
+            if *name == ~"*" { wildcard = Some(q); }
+        }
+
+        match wildcard {
+            Some(q) => q > 0f64,
+            None => false,
+        }
+    }
+
+    // Every cookie the client sent, in order. RFC 2109 clients may follow a
+    // cookie with $Path and $Domain attributes, which are attached to it.
+    pub fn request_cookies(&self) -> ~[RequestCookie] {
+        let mut cookies: ~[RequestCookie] = ~[];
+
+        let values = match self.header("cookie") {
+            Some(values) => values,
+            None => return cookies,
+        };
+
+        for value in values.iter() {
+            for pair in value.split_iter(';') {
+                let pair = pair.trim();
+                let (name, value) = match pair.find('=') {
+                    Some(i) => (pair.slice_to(i).trim(), pair.slice_from(i + 1u).trim()),
+                    None => (pair, ""),
+                };
+                if name.is_empty() { loop; }
+
+                let value = if value.len() >= 2u && value.starts_with("\"") &&
+                               value.ends_with("\"") {
+                    value.slice(1u, value.len() - 1u)
+                } else {
+                    value
+                };
+
+                if name.starts_with("$") {
+                    let len = cookies.len();
+                    if len > 0u {
+                        let cookie = &mut cookies[len - 1u];
+                        if name.eq_ignore_ascii_case("$Path") {
+                            cookie.path = Some(value.to_owned());
+                        } else if name.eq_ignore_ascii_case("$Domain") {
+                            cookie.domain = Some(value.to_owned());
+                        }
+                    }
+                    loop;
+                }
+
+                cookies.push(RequestCookie {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                    path: None,
+                    domain: None,
+                });
+            }
+        }
+
+        cookies
+    }
+
+    // Maps cookie names to their values. Should a name be sent twice, the
+    // first one wins, as it is the one with the most specific path.
+    pub fn cookies(&self) -> HashMap<~str, ~str> {
+        let mut cookies = HashMap::new();
+        for cookie in self.request_cookies().consume_iter() {
+            cookies.find_or_insert(cookie.name, cookie.value);
+        }
+        cookies
+    }
+
+    // The unit of the Range header, such as "bytes" or a custom unit like
+    // "items".
+    pub fn range_unit(&self) -> Option<~str> {
+        let range = match self.header_first("range") {
+            Some(range) => range,
+            None => return None,
+        };
+
+        match range.find('=') {
+            Some(i) if i > 0u => Some(range.slice_to(i).trim().to_ascii_lower()),
+            _ => None,
+        }
+    }
+
+    // The id of the last server-sent event a reconnecting client saw.
+    pub fn last_event_id(&self) -> Option<~str> {
+        self.header_first("last-event-id")
+    }
+
+    // The DNT header: "1" opts out of tracking, "0" consents to it.
+    pub fn do_not_track(&self) -> Option<bool> {
+        match self.header_first("dnt") {
+            Some(value) => {
+                match value.trim() {
+                    "1" => Some(true),
+                    "0" => Some(false),
+                    _ => None,
+                }
+            }
+            None => None,
+        }
+    }
+
+    // True when the client asked for a fresh response with Cache-Control:
+    // no-cache, or the older Pragma: no-cache if it sent no Cache-Control.
+    pub fn request_no_cache(&self) -> bool {
+        let directives = match self.header_list("cache-control") {
+            Some(directives) => directives,
+            None => match self.header_list("pragma") {
+                Some(directives) => directives,
+                None => return false,
+            },
+        };
+
+        directives.iter().any(|directive| directive.eq_ignore_ascii_case("no-cache"))
+    }
+
+    // Looks up a preference from the Prefer header (RFC 7240), returning its
+    // value, or an empty string for a bare token like respond-async.
+    pub fn prefer(&self, token: &str) -> Option<~str> {
+        let items = match self.header_list("prefer") {
+            Some(items) => items,
+            None => return None,
+        };
+
+        for item in items.iter() {
+            let preference = match item.find(';') {
+                Some(i) => item.slice_to(i),
+                None => item.as_slice(),
+            };
+
+            let (name, value) = match preference.find('=') {
+                Some(i) => (preference.slice_to(i).trim(), preference.slice_from(i + 1u).trim()),
+                None => (preference.trim(), ""),
+            };
+
+            if name.eq_ignore_ascii_case(token) {
+                return Some(value.trim_chars(&'"').to_owned());
+            }
+        }
+
+        None
+    }
+
+    // Every value sent for the cookie `name`, in order.
+    pub fn cookie_all(&self, name: &str) -> ~[~str] {
+        let mut values = ~[];
+        for cookie in self.request_cookies().consume_iter() {
+            if cookie.name.as_slice() == name { values.push(cookie.value); }
+        }
+        values
+    }
+
+    // The charsets the client listed, with their quality values, in the
+    // order they were sent.
+    pub fn accept_charset(&self) -> ~[(~str, f64)] {
+        match self.header_list("accept-charset") {
+            Some(items) => parse_weighted(items),
+            None => ~[],
+        }
+    }
+
+    // Picks the charset from `supported` the client rates highest, falling
+    // back on the order of `supported` to break ties. A client that sent no
+    // Accept-Charset is happy with anything.
+    pub fn prefers_charset(&self, supported: &[~str]) -> Option<~str> {
+        let accepted = self.accept_charset();
+        if accepted.is_empty() {
+            return if supported.is_empty() { None } else { Some(supported[0u].clone()) };
+        }
+
+        let mut best = None;
+        let mut best_q = 0f64;
+
+        for charset in supported.iter() {
+            let mut exact = None;
+            let mut wildcard = None;
+
+            for accept in accepted.iter() {
+                let (ref name, q) = *accept;
+                if name.eq_ignore_ascii_case(*charset) {
+                    exact = Some(q);
+                } else if *name == ~"*" {
+                    wildcard = Some(q);
+                }
+            }
+
+            let q = match (exact, wildcard) {
+                (Some(q), _) => q,
+                (None, Some(q)) => q,
+                (None, None) => 0f64,
+            };
+
+            if q > best_q {
+                best = Some(charset.clone());
+                best_q = q;
+            }
+        }
+
+        best
+    }
+
+    // Hands each part of a multipart body to `f` as it is found, with the
+    // part's headers and a reader over its data, rather than collecting
+    // every part up front. Mongrel2 has already buffered the whole body, so
+    // this saves copying the parts, not holding the upload in memory.
+    pub fn multipart_streaming(&self,
+                               f: &fn(&Headers, @io::Reader)) -> Result<(), ~str> {
+        let boundary = match self.multipart_boundary() {
+            Some(boundary) => boundary,
+            None => return Err(~"request is not multipart"),
+        };
+
+        let delimiter = str::to_bytes("--" + boundary);
+        let body = self.body.as_slice();
+
+        let mut pos = match find_bytes(body, delimiter) {
+            Some(i) => i + delimiter.len(),
+            None => return Err(~"missing multipart boundary"),
+        };
+
+        let mut next_delimiter = str::to_bytes("\r\n");
+        next_delimiter.push_all(delimiter);
+
+        loop {
+            let rest = body.slice(pos, body.len());
+            if bytes_start_with(rest, "--".as_bytes()) { return Ok(()); }
+            if !bytes_start_with(rest, "\r\n".as_bytes()) {
+                return Err(~"malformed multipart boundary");
+            }
+
+            let part = rest.slice(2u, rest.len());
+            let end = match find_bytes(part, next_delimiter) {
+                Some(end) => end,
+                None => return Err(~"unterminated multipart part"),
+            };
+            let part = part.slice(0u, end);
+
+            let (headers, data) = match find_bytes(part, "\r\n\r\n".as_bytes()) {
+                Some(i) => (part.slice(0u, i), part.slice(i + 4u, part.len())),
+                None => return Err(~"malformed multipart part headers"),
+            };
+
+            let headers = match parse_part_headers(headers) {
+                Ok(headers) => headers,
+                Err(e) => return Err(e),
+            };
+
+            io::with_bytes_reader(data, |rdr| f(&headers, rdr));
+
+            pos += 2u + end + next_delimiter.len();
+        }
+    }
+
+    fn multipart_boundary(&self) -> Option<~str> {
+        let content_type = match self.header_first("content-type") {
+            Some(content_type) => content_type,
+            None => return None,
+        };
+
+        let mut params = content_type.split_iter(';');
+        match params.next() {
+            Some(mime) if mime.trim().to_ascii_lower().starts_with("multipart/") => { },
+            _ => return None,
+        }
+
+        for param in params {
+            let param = param.trim();
+            if param.len() > 9u && starts_with_ignore_case(param, "boundary=") {
+                let boundary = param.slice_from(9u).trim_chars(&'"');
+                if !boundary.is_empty() { return Some(boundary.to_owned()); }
+            }
+        }
+
+        None
+    }
+
+    // Returns the decoded query parameters in the order they were sent,
+    // repeated keys included.
+    pub fn query_pairs(&self) -> ~[(~str, ~str)] {
+        match self.query_string() {
+            Some(query) => parse_query(query),
+            None => ~[],
+        }
+    }
+
+    // Groups the decoded query parameters by name, the way headers are
+    // stored.
+    pub fn query(&self) -> HashMap<~str, ~[~str]> {
+        let mut params = HashMap::new();
+
+        for pair in self.query_pairs().consume_iter() {
+            let (key, value) = pair;
+            params.mangle(key, value,
+                |_, value| ~[value],
+                |_, values, value| values.push(value));
+        }
+
+        params
+    }
+
+    pub fn path_only(&self) -> ~str {
+        match self.path.find('?') {
+            Some(i) => self.path.slice_to(i).to_owned(),
+            None => self.path.clone(),
+        }
+    }
+
+    // The rest of the path after `prefix`, which must end on a segment
+    // boundary, so "/api" matches "/api/users" but not "/apiv2". The query
+    // string is left off and the remainder always starts with a '/'.
+    pub fn strip_prefix(&self, prefix: &str) -> Option<~str> {
+        let path = self.path_only();
+
+        match path_under(path, prefix) {
+            Some("") => Some(~"/"),
+            Some(rest) => Some(rest.to_owned()),
+            None => None,
+        }
+    }
+
+    // Mongrel2 passes the query string in its QUERY header, but fall back
+    // on the path in case a message was built without it.
+    fn query_string(&self) -> Option<~str> {
+        match self.header_first("QUERY") {
+            Some(query) => Some(query),
+            None => {
+                match self.path.find('?') {
+                    Some(i) => Some(self.path.slice_from(i + 1u).to_owned()),
+                    None => None,
+                }
+            }
+        }
+    }
+
+    fn query_param(&self, name: &str) -> Option<~str> {
+        let query = match self.query_string() {
+            Some(query) => query,
+            None => return None,
+        };
+
+        for pair in parse_query(query).consume_iter() {
+            let (key, value) = pair;
+            if key.as_slice() == name { return Some(value); }
+        }
+
+        None
+    }
+
+    // Collects every comma separated element of a header across all of its
+    // values.
+    fn header_list(&self, name: &str) -> Option<~[~str]> {
+        match self.header(name) {
+            None => None,
+            Some(values) => {
+                let mut items = ~[];
+                for value in values.iter() {
+                    items.push_all_move(split_list(*value));
+                }
+                Some(items)
+            }
+        }
+    }
+}
+
+// Splits list items like "iso-8859-1;q=0.5" into a lowercased name and its
+// quality, which defaults to 1. Items with a malformed quality are dropped.
+fn parse_weighted(items: &[~str]) -> ~[(~str, f64)] {
+    let mut weighted = ~[];
+
+    for item in items.iter() {
+        let mut params = item.split_iter(';').map(|p| p.trim());
+        let name = match params.next() {
+            Some(name) => name.to_ascii_lower(),
+            None => ~"",
+        };
+        if name.is_empty() { loop; }
+
+        let mut q = Some(1f64);
+        for param in params {
+            if param.len() > 2u && starts_with_ignore_case(param, "q=") {
+                q = match f64::from_str(param.slice_from(2u)) {
+                    Some(q) if q >= 0f64 && q <= 1f64 => Some(q),
+                    _ => None,
+                };
+            }
+        }
+
+        match q {
+            Some(q) => weighted.push((name, q)),
+            None => { },
+        }
+    }
+
+    weighted
+}
+
+// Splits a comma separated header value, ignoring commas inside quoted
+// strings and dropping empty elements.
+fn split_list(value: &str) -> ~[~str] {
+    let mut items = ~[];
+    let mut item = ~"";
+    let mut quoted = false;
+
+    for ch in value.iter() {
+        if ch == '"' { quoted = !quoted; }
+
+        if ch == ',' && !quoted {
+            let trimmed = item.trim().to_owned();
+            if !trimmed.is_empty() { items.push(trimmed); }
+            item = ~"";
+        } else {
+            item.push_char(ch);
+        }
+    }
+
+    let trimmed = item.trim().to_owned();
+    if !trimmed.is_empty() { items.push(trimmed); }
+
+    items
+}
+
+fn parse(bytes: &[u8]) -> Result<Request, ~str> {
+    parse_limited(bytes, None, ~[])
+}
+
+// The body is copied into `body`, an empty buffer that may come from a
+// BufferPool.
+fn parse_limited(bytes: &[u8],
+                 max_body_size: Option<uint>,
+                 body: ~[u8]) -> Result<Request, ~str> {
+    // Mongrel2 normally sends tnetstring headers, which can be read straight
+    // out of the message without building an intermediate tnetstring map.
+    // Anything else takes the general path, errors included.
+    let request = match parse_view(bytes) {
+        Ok(view) => {
+            match request_from_view(&view, max_body_size, body) {
+                Ok(request) => {
+                    let mut request = request;
+                    request.raw_headers = Some(view.raw_headers.to_owned());
+                    Ok(request)
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Err(_) => io::with_bytes_reader(bytes, |rdr| {
+            parse_reader(rdr, max_body_size, Some(bytes))
+        }),
+    };
+
+    match request {
+        Ok(request) => {
+            let mut request = request;
+            request.wire_size = bytes.len();
+            Ok(request)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn request_from_view(view: &RequestView,
+                     max_body_size: Option<uint>,
+                     body: ~[u8]) -> Result<Request, ~str> {
+    let mut headers = HashMap::with_capacity(view.headers.len());
+
+    for header in view.headers.iter() {
+        let (key, value) = *header;
+        headers.mangle(key.to_owned(), value,
+            |_, value| ~[value.to_owned()],
+            |_, values, value| values.push(value.to_owned()));
+    }
+
+    let mut body = body;
+    body.push_all(view.body);
+
+    make_request(view.uuid.to_owned(),
+                 view.id.to_owned(),
+                 view.path.to_owned(),
+                 headers,
+                 body,
+                 max_body_size)
+}
+
+// `raw` is the message `rdr` reads from, given when the header block
+// should be kept.
+fn parse_reader(rdr: @io::Reader,
+                max_body_size: Option<uint>,
+                raw: Option<&[u8]>) -> Result<Request, ~str> {
+    let uuid = match parse_uuid(rdr) {
+        Ok(uuid) => uuid,
+        Err(e) => return Err(e),
+    };
+
+    let id = match parse_id(rdr) {
+        Ok(value) => value,
+        Err(e) => return Err(e),
+    };
+
+    let path = match parse_path(rdr) {
+        Ok(value) => value,
+        Err(e) => return Err(e),
+    };
+
+    let headers_start = rdr.tell();
+    let headers = match parse_headers(rdr) {
+        Ok(headers) => headers,
+        Err(e) => return Err(e),
+    };
+    let headers_end = rdr.tell();
+
+    let body = match parse_body(rdr) {
+        Ok(body) => body,
+        Err(e) => return Err(e),
+    };
+
+    match make_request(uuid, id, path, headers, body, max_body_size) {
+        Ok(request) => {
+            let mut request = request;
+            match raw {
+                Some(bytes) =>
+                    request.raw_headers = Some(bytes.slice(headers_start, headers_end).to_owned()),
+                None => { },
+            }
+            Ok(request)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn make_request(uuid: ~str,
+                id: ~str,
+                path: ~str,
+                headers: Headers,
+                body: ~[u8],
+                max_body_size: Option<uint>) -> Result<Request, ~str> {
+    let body = if is_chunked(&headers) {
+        match dechunk(body, max_body_size) {
+            Ok(body) => body,
+            Err(e) => return Err(e),
+        }
+    } else {
+        body
+    };
+
+    match max_body_size {
+        Some(max) if body.len() > max => return Err(~"body exceeds maximum size"),
+        _ => { },
+    }
+
+    // Extract out the json body if we have it.
+    let json_body = match headers.find(&~"METHOD") {
+      None => None,
+      Some(method) => {
+        if method.len() == 1u && method[0u] == ~"JSON" {
+            match parse_json_limited(body, JSON_MAX_DEPTH, JSON_MAX_SIZE) {
+              Ok(json::Object(map)) => Some(map),
+              Ok(_) => return Err(~"invalid JSON body: not a dictionary"),
+              Err(e) => return Err(fmt!("invalid JSON body: %s", e)),
+            }
+        } else { None }
+      }
+    };
+
+    let request = Request {
+        uuid: uuid,
+        id: id,
+        path: path,
+        headers: headers,
+        body: body,
+        json_body: json_body,
+        sequence: None,
+        wire_size: 0u,
+        raw_headers: None
+    };
+
+    // Disagreeing lengths are a request smuggling vector, so refuse to guess
+    // which one is right.
+    if request.has_conflicting_content_length() {
+        return Err(~"conflicting content-length headers");
+    }
+
+    Ok(request)
+}
+
+fn is_safe_callback(name: &str) -> bool {
+    if name.is_empty() || name.len() > 128u { return false; }
+
+    do name.split_iter('.').all |part| {
+        !part.is_empty() &&
+            !part.char_at(0u).is_digit() &&
+            part.iter().all(|ch| ch.is_ascii() &&
+                                 (ch.is_alphanumeric() || ch == '_' || ch == '$'))
+    }
+}
+
+// Splits a query string into its decoded key/value pairs, in order. A bare
+// key maps to an empty value.
+fn parse_query(query: &str) -> ~[(~str, ~str)] {
+    let mut pairs = ~[];
+
+    for pair in query.split_iter('&') {
+        if pair.is_empty() { loop; }
+
+        let (key, value) = match pair.find('=') {
+            Some(i) => (pair.slice_to(i), pair.slice_from(i + 1u)),
+            None => (pair, ""),
+        };
+
+        pairs.push((url_decode(key), url_decode(value)));
+    }
+
+    pairs
+}
+
+// Percent encodes everything but the unreserved characters of RFC 3986.
+fn url_encode(s: &str) -> ~str {
+    let mut out = ~"";
+
+    for b in s.as_bytes().iter() {
+        let c = *b as char;
+        if c.is_alphanumeric() && *b < 0x80u8 || c == '-' || c == '.' || c == '_' || c == '~' {
+            out.push_char(c);
+        } else {
+            out.push_str(fmt!("%%%02X", *b as uint));
+        }
+    }
+
+    out
+}
+
+// Decodes %XX escapes and '+' as a space. Input that doesn't decode to
+// valid UTF-8 is returned unchanged.
+fn url_decode(s: &str) -> ~str {
+    let bytes = s.as_bytes();
+    let mut out = ~[];
+    let mut i = 0u;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+
+        if b == '+' as u8 {
+            out.push(' ' as u8);
+        } else if b == '%' as u8 && i + 2u < bytes.len() {
+            match (hex_value(bytes[i + 1u]), hex_value(bytes[i + 2u])) {
+                (Some(hi), Some(lo)) => {
+                    out.push((hi << 4u | lo) as u8);
+                    i += 2u;
+                }
+                _ => out.push(b),
+            }
+        } else {
+            out.push(b);
+        }
+
+        i += 1u;
+    }
+
+    if str::is_utf8(out) { str::from_bytes(out) } else { s.to_owned() }
+}
+
+fn hex_value(b: u8) -> Option<uint> {
+    match b as char {
+        '0'..'9' => Some(b as uint - '0' as uint),
+        'a'..'f' => Some(b as uint - 'a' as uint + 10u),
+        'A'..'F' => Some(b as uint - 'A' as uint + 10u),
+        _ => None,
+    }
+}
+
+// Parses the header lines of a multipart part, lowercasing the names the way
+// Mongrel2 does for request headers.
+fn parse_part_headers(bytes: &[u8]) -> Result<Headers, ~str> {
+    let mut headers = HashMap::new();
+
+    let text = match view_str(bytes) {
+        Ok(text) => text,
+        Err(e) => return Err(e),
+    };
+
+    for line in text.split_str_iter("\r\n") {
+        if line.is_empty() { loop; }
+
+        let (name, value) = match line.find(':') {
+            Some(i) => (line.slice_to(i).trim(), line.slice_from(i + 1u).trim()),
+            None => return Err(~"malformed multipart header"),
+        };
+
+        headers.mangle(name.to_ascii_lower(), value,
+            |_, value| ~[value.to_owned()],
+            |_, values, value| values.push(value.to_owned()));
+    }
+
+    Ok(headers)
+}
+
+// Compares bytes rather than slicing `s`, which would fail the task when a
+// multibyte character straddles the end of the prefix.
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    let (s, prefix) = (s.as_bytes(), prefix.as_bytes());
+    s.len() >= prefix.len() &&
+        range(0u, prefix.len()).all(|i| ascii_lower(s[i]) == ascii_lower(prefix[i]))
+}
+
+fn ascii_lower(byte: u8) -> u8 {
+    if byte >= 'A' as u8 && byte <= 'Z' as u8 { byte + 32u8 } else { byte }
+}
+
+fn bytes_start_with(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && bytes.slice(0u, prefix.len()) == prefix
+}
+
+// What is left of `path` after `prefix`, if the prefix ends on a segment
+// boundary, so "/api" covers "/api/users" but not "/apiary".
+fn path_under<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_right_chars(&'/');
+    if !path.starts_with(prefix) { return None; }
+
+    let rest = path.slice_from(prefix.len());
+    if rest.is_empty() || rest.starts_with("/") || rest.starts_with("?") {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+fn bytes_ends_with(bytes: &[u8], suffix: &[u8]) -> bool {
+    bytes.len() >= suffix.len() && bytes.slice_from(bytes.len() - suffix.len()) == suffix
+}
+
+// Knuth-Morris-Pratt, so a body full of near misses for a long boundary
+// still takes time linear in its length.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+    if needle.is_empty() { return Some(0u); }
+    if needle.len() > haystack.len() { return None; }
+
+    // fallback[i] is the length of the longest proper prefix of
+    // needle[..i + 1] that is also a suffix of it.
+    let mut fallback = vec::from_elem(needle.len(), 0u);
+    let mut matched = 0u;
+    for i in range(1u, needle.len()) {
+        while matched > 0u && needle[i] != needle[matched] {
+            matched = fallback[matched - 1u];
+        }
+        if needle[i] == needle[matched] { matched += 1u; }
+        fallback[i] = matched;
+    }
+
+    let mut matched = 0u;
+    for (i, byte) in haystack.iter().enumerate() {
+        while matched > 0u && *byte != needle[matched] {
+            matched = fallback[matched - 1u];
+        }
+        if *byte == needle[matched] { matched += 1u; }
+        if matched == needle.len() { return Some(i + 1u - matched); }
+    }
+
+    None
+}
+
+static JSON_MAX_DEPTH: uint = 64u;
+static JSON_MAX_SIZE: uint = 1048576u;
+
+// The JSON parser recurses for every nested array or object, so the nesting
+// is checked up front before a hostile body can exhaust the stack.
+pub fn parse_json_limited(bytes: &[u8],
+                          max_depth: uint,
+                          max_size: uint) -> Result<json::Json, ~str> {
+    if bytes.len() > max_size {
+        return Err(fmt!("JSON is larger than %u bytes", max_size));
+    }
+
+    let mut depth = 0u;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for b in bytes.iter() {
+        let ch = *b as char;
+
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == '"' {
+                in_string = false;
+            }
+        } else if ch == '"' {
+            in_string = true;
+        } else if ch == '[' || ch == '{' {
+            depth += 1u;
+            if depth > max_depth {
+                return Err(fmt!("JSON is nested deeper than %u levels", max_depth));
+            }
+        } else if (ch == ']' || ch == '}') && depth > 0u {
+            depth -= 1u;
+        }
+    }
+
+    if !str::is_utf8(bytes) { return Err(~"JSON is not valid UTF-8"); }
+
+    match json::from_str(str::from_bytes(bytes)) {
+        Ok(json) => Ok(json),
+        Err(e) => Err(e.to_str()),
+    }
+}
+
+// Header names keep the case they arrived in, so as with Request::header
+// the lookup ignores it.
+fn is_chunked(headers: &Headers) -> bool {
+    for (key, values) in headers.iter() {
+        if !key.eq_ignore_ascii_case("transfer-encoding") { loop; }
+
+        for value in values.iter() {
+            if split_list(*value).iter().any(|coding| coding.eq_ignore_ascii_case("chunked")) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// Decodes a chunked transfer-encoded body. Trailers are discarded. The size
+// limit is checked as each chunk arrives, so a flood of small chunks is
+// stopped as soon as it goes over.
+fn dechunk(bytes: &[u8], max_body_size: Option<uint>) -> Result<~[u8], ~str> {
+    let mut body = ~[];
+    let mut rest = bytes;
+
+    loop {
+        let (line, next) = match split_line(rest) {
+            Some(split) => split,
+            None => return Err(~"truncated chunk size"),
+        };
+
+        // Chunk extensions after a ';' carry nothing we use.
+        let line = match line.position_elem(&(';' as u8)) {
+            Some(i) => line.slice_to(i),
+            None => line,
+        };
+
+        let size = match view_str(line) {
+            Ok(size) => uint::from_str_radix(size.trim(), 16u),
+            Err(_) => None,
+        };
+
+        let size = match size {
+            Some(size) => size,
+            None => return Err(~"invalid chunk size"),
+        };
+
+        if size == 0u { return Ok(body); }
+
+        match max_body_size {
+            Some(max) if size > max - body.len() =>
+                return Err(~"chunked body exceeds maximum size"),
+            _ => { },
+        }
+
+        if size > next.len() || next.len() - size < 2u ||
+           next[size] != '\r' as u8 ||
+           next[size + 1u] != '\n' as u8 {
+            return Err(~"truncated chunk");
+        }
+
+        body.push_all(next.slice(0u, size));
+        rest = next.slice(size + 2u, next.len());
+    }
+}
+
+// Splits off everything up to the next CRLF.
+fn split_line<'a>(bytes: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let mut i = 0u;
+    while i + 1u < bytes.len() {
+        if bytes[i] == '\r' as u8 && bytes[i + 1u] == '\n' as u8 {
+            return Some((bytes.slice(0u, i), bytes.slice(i + 2u, bytes.len())));
+        }
+        i += 1u;
+    }
+    None
+}
+
+fn read_str(rdr: @io::Reader) -> Option<~str> {
+    let mut s = ~"";
+
+    while !rdr.eof() {
+        let ch = rdr.read_char();
+        if ch == ' ' {
+            return Some(s);
+        } else {
+            s.push_char(ch);
+        }
+    }
+
+    None
+}
+
+fn parse_uuid(rdr: @io::Reader) -> Result<~str, ~str> {
+    match read_str(rdr) {
+        Some(s) => Ok(s),
+        None => Err(~"invalid sender uuid: missing or truncated"),
+    }
+}
+
+fn parse_id(rdr: @io::Reader) -> Result<~str, ~str> {
+    match read_str(rdr) {
+        Some(s) => Ok(s),
+        None => Err(~"invalid connection id: missing or truncated"),
+    }
+}
+
+fn parse_path(rdr: @io::Reader) -> Result<~str, ~str> {
+    match read_str(rdr) {
+        Some(s) => Ok(s),
+        None => Err(~"invalid path: missing or truncated"),
+    }
+}
+
+fn parse_headers(rdr: @io::Reader) -> Result<Headers, ~str> {
+    let tns = match tnetstring::from_reader(rdr) {
+        None => return Err(~"invalid headers: missing or truncated netstring"),
+        Some(tns) => tns,
+    };
+
+    match tns {
+        tnetstring::Map(map) => parse_tnetstring_headers(map),
+
+        // Fall back onto json if we got a string.
+        tnetstring::Str(bytes) => {
+            if !str::is_utf8(bytes) {
+                return Err(~"invalid headers: JSON is not valid UTF-8");
+            }
+
+            match json::from_str(str::from_bytes(bytes)) {
+                Err(e) => return Err(fmt!("invalid headers: %s", e.to_str())),
+                Ok(json::Object(map)) => parse_json_headers(map),
+                Ok(_) => Err(~"invalid headers: JSON is not a dictionary"),
+            }
+        }
+
+        _ => Err(~"invalid headers: not a dictionary"),
+    }
+}
+
+fn parse_tnetstring_headers(map: tnetstring::Map) -> Result<Headers, ~str> {
+    let mut headers = HashMap::new();
+
+    for (key, value) in map.iter() {
+        if !str::is_utf8(*key) {
+            return Err(~"invalid headers: header name is not valid UTF-8");
+        }
+        let key = str::from_bytes(*key);
+        let mut values = match headers.pop(&key) {
+            Some(values) => values,
+            None => ~[],
+        };
+
+        match value {
+            &tnetstring::Str(ref v) if str::is_utf8(*v) =>
+                values.push(str::from_bytes(*v)),
+            &tnetstring::Vec(ref vs) => {
+                for v in vs.iter() {
+                    match v {
+                        &tnetstring::Str(ref v) if str::is_utf8(*v) =>
+                            values.push(str::from_bytes(*v)),
+                        _ => return Err(fmt!("invalid headers: value of %s is not a string",
+                                             key)),
+                    }
+                }
+            },
+            _ => return Err(fmt!("invalid headers: value of %s is not a string", key)),
+        }
+
+        headers.insert(key, values);
+    }
+
+    Ok(headers)
+}
+
+fn parse_json_headers(map: ~json::Object) -> Result<Headers, ~str> {
+    let mut headers = HashMap::new();
+
+    for (key, value) in map.iter() {
+        let mut values = match headers.pop(key) {
+            Some(values) => values,
+            None => ~[],
+        };
+
+        match value {
+            &json::String(ref v) => values.push(v.clone()),
+            &json::List(ref vs) => {
+                for v in vs.iter() {
+                    match v {
+                        &json::String(ref v) => values.push(v.clone()),
+                        _ => return Err(fmt!("invalid headers: value of %s is not a string",
+                                             *key)),
+                    }
+                }
+            }
+            _ => return Err(fmt!("invalid headers: value of %s is not a string", *key)),
+        }
+
+        headers.insert(key.clone(), values);
+    }
+
+    Ok(headers)
+}
+
+fn parse_body(rdr: @io::Reader) -> Result<~[u8], ~str> {
+    match tnetstring::from_reader(rdr) {
+        None => Err(~"invalid body: missing or truncated netstring"),
+        Some(tns) => {
+            match tns {
+                tnetstring::Str(body) => Ok(body),
+                _ => Err(~"invalid body: not a string"),
+            }
+        }
+    }
+}
+
+// A read-only view of a request that borrows everything from the raw message
+// instead of copying it. Only tnetstring encoded headers can be borrowed, so
+// messages with JSON headers must go through `parse`.
+pub struct RequestView<'self> {
+    uuid: &'self str,
+    id: &'self str,
+    path: &'self str,
+    headers: ~[(&'self str, &'self str)],
+    body: &'self [u8],
+    raw_headers: &'self [u8],
+}
+
+impl<'self> RequestView<'self> {
+    pub fn header(&self, name: &str) -> Option<&'self str> {
+        for header in self.headers.iter() {
+            let (key, value) = *header;
+            if key == name { return Some(value); }
+        }
+        None
+    }
+
+    // The body size from its tnetstring length prefix. The view never copies
+    // or decodes the body, so this lets a handler decide whether to bother
+    // with the body before materializing a `Request`. A chunked body counts
+    // at its encoded size. When the body is empty, as it is while Mongrel2
+    // spools a large upload to disk, Content-Length is used instead, and an
+    // unreadable Content-Length gives `None`.
+    pub fn declared_body_size(&self) -> Option<uint> {
+        if !self.body.is_empty() { return Some(self.body.len()); }
+
+        for header in self.headers.iter() {
+            let (key, value) = *header;
+            if key.eq_ignore_ascii_case("content-length") {
+                return uint::from_str(value.trim());
+            }
+        }
+
+        Some(0u)
+    }
+
+    pub fn header_values(&self, name: &str) -> ~[&'self str] {
+        let mut values = ~[];
+        for header in self.headers.iter() {
+            let (key, value) = *header;
+            if key == name { values.push(value); }
+        }
+        values
+    }
+}
+
+pub fn parse_view<'a>(bytes: &'a [u8]) -> Result<RequestView<'a>, ~str> {
+    let (uuid, rest) = match split_word(bytes) {
+        Some(split) => split,
+        None => return Err(~"invalid sender uuid"),
+    };
+
+    let (id, rest) = match split_word(rest) {
+        Some(split) => split,
+        None => return Err(~"invalid connection id"),
+    };
+
+    let (path, rest) = match split_word(rest) {
+        Some(split) => split,
+        None => return Err(~"invalid path"),
+    };
+
+    let (headers, raw_headers, rest) = match split_tnetstring(rest) {
+        Ok((payload, '}', after)) => {
+            match parse_view_headers(payload) {
+                Ok(headers) => (headers, rest.slice_to(rest.len() - after.len()), after),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(_) => return Err(~"headers are not a tnetstring dictionary"),
+        Err(e) => return Err(e),
+    };
+
+    let body = match split_tnetstring(rest) {
+        Ok((payload, ',', _)) => payload,
+        Ok(_) => return Err(~"invalid body"),
+        Err(e) => return Err(e),
+    };
+
+    Ok(RequestView {
+        uuid: match view_str(uuid) { Ok(s) => s, Err(e) => return Err(e) },
+        id: match view_str(id) { Ok(s) => s, Err(e) => return Err(e) },
+        path: match view_str(path) { Ok(s) => s, Err(e) => return Err(e) },
+        headers: headers,
+        body: body,
+        raw_headers: raw_headers,
+    })
+}
+
+fn parse_view_headers<'a>(bytes: &'a [u8]) -> Result<~[(&'a str, &'a str)], ~str> {
+    let mut headers = ~[];
+    let mut rest = bytes;
+
+    while !rest.is_empty() {
+        let (key, value, typ, next) = match split_tnetstring(rest) {
+            Ok((key, ',', next)) => {
+                match split_tnetstring(next) {
+                    Ok((value, typ, next)) => (key, value, typ, next),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(_) => return Err(~"header key is not a string"),
+            Err(e) => return Err(e),
+        };
+
+        let key = match view_str(key) {
+            Ok(key) => key,
+            Err(e) => return Err(e),
+        };
+
+        match typ as char {
+            ',' => {
+                match view_str(value) {
+                    Ok(value) => headers.push((key, value)),
+                    Err(e) => return Err(e),
+                }
+            }
+            ']' => {
+                let mut values = value;
+                while !values.is_empty() {
+                    match split_tnetstring(values) {
+                        Ok((v, ',', next)) => {
+                            match view_str(v) {
+                                Ok(v) => headers.push((key, v)),
+                                Err(e) => return Err(e),
+                            }
+                            values = next;
+                        }
+                        Ok(_) => return Err(~"header value is not a string"),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            _ => return Err(~"header value is not string"),
+        }
+
+        rest = next;
+    }
+
+    Ok(headers)
+}
+
+// Splits off everything up to the next space.
+// The sender uuid and connection id at the front of a message, if they can
+// be read even though the rest of it can't.
+fn message_target(bytes: &[u8]) -> Option<(~str, ~str)> {
+    let (uuid, rest) = match split_word(bytes) {
+        Some(split) => split,
+        None => return None,
+    };
+    let (id, _) = match split_word(rest) {
+        Some(split) => split,
+        None => return None,
+    };
+
+    match (view_str(uuid), view_str(id)) {
+        (Ok(uuid), Ok(id)) if !uuid.is_empty() && !id.is_empty() =>
+            Some((uuid.to_owned(), id.to_owned())),
+        _ => None,
+    }
+}
+
+fn split_word<'a>(bytes: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+    match bytes.position_elem(&(' ' as u8)) {
+        None => None,
+        Some(i) => Some((bytes.slice(0u, i), bytes.slice(i + 1u, bytes.len()))),
+    }
+}
+
+// Splits the tnetstring at the front of `bytes` into its payload, its type
+// tag and whatever follows it.
+fn split_tnetstring<'a>(bytes: &'a [u8]) -> Result<(&'a [u8], char, &'a [u8]), ~str> {
+    let mut len = 0u;
+    let mut i = 0u;
+
+    while i < bytes.len() && bytes[i] != ':' as u8 {
+        let b = bytes[i];
+        if b < '0' as u8 || b > '9' as u8 || i >= 9u {
+            return Err(~"invalid tnetstring length");
+        }
+        len = len * 10u + (b - '0' as u8) as uint;
+        i += 1u;
+    }
+
+    if i == 0u || i == bytes.len() {
+        return Err(~"invalid tnetstring length");
+    }
+
+    let start = i + 1u;
+    if start + len >= bytes.len() {
+        return Err(~"truncated tnetstring");
+    }
+
+    Ok((bytes.slice(start, start + len),
+        bytes[start + len] as char,
+        bytes.slice(start + len + 1u, bytes.len())))
+}
+
+fn view_str<'a>(bytes: &'a [u8]) -> Result<&'a str, ~str> {
+    if str::is_utf8(bytes) {
+        Ok(str::from_bytes_slice(bytes))
+    } else {
+        Err(~"invalid utf-8")
+    }
+}
+
+#[test]
+fn test() {
+    let ctx = zmq::init(1).unwrap();
+
+    let mut connection = connect(ctx,
+        Some(~"F0D32575-2ABB-4957-BC8B-12DAC8AFF13A"),
+        ~[~"tcp://127.0.0.1:9998"],
+        ~[~"tcp://127.0.0.1:9999"]);
+
+    connection.term();
+    ctx.term();
+}
+
+#[test]
+fn test_request_parse() {
+    let request = parse(
+        str::to_bytes("abCD-123 56 / 13:{\"foo\":\"bar\"},11:hello world,")
+    ).unwrap();
+
+    assert!(request.uuid == ~"abCD-123");
+    assert!(request.id == ~"56");
+    assert!(request.headers.len() == 1u);
+    let value = match request.headers.find(&~"foo") {
+        Some(header_list) => header_list[0u].clone(),
+        None => ~"",
+    };
+    assert!(value == ~"bar");
+    assert!(request.body == str::to_bytes("hello world"));
+}
+
+#[cfg(test)]
+fn test_request(path: &str, headers: &[(&str, &str)], body: &[u8]) -> Request {
+    let mut map = HashMap::new();
+
+    for header in headers.iter() {
+        let (key, value) = *header;
+        let mut values = match map.pop(&key.to_owned()) {
+            Some(values) => values,
+            None => ~[],
+        };
+        values.push(value.to_owned());
+        map.insert(key.to_owned(), values);
+    }
+
+    Request {
+        uuid: ~"abCD-123",
+        id: ~"56",
+        path: path.to_owned(),
+        headers: map,
+        body: body.to_owned(),
+        json_body: None,
+        sequence: None,
+        wire_size: 0u,
+        raw_headers: None
+    }
+}
+
+#[cfg(test)]
+fn with_test_connection(f: &fn(&mut Connection)) {
+    let ctx = zmq::init(1).unwrap();
+
+    let mut connection = connect(ctx,
+        Some(~"F0D32575-2ABB-4957-BC8B-12DAC8AFF13A"),
+        ~[~"tcp://127.0.0.1:9998"],
+        ~[~"tcp://127.0.0.1:9999"]);
+
+    f(&mut connection);
+
+    connection.term();
+    ctx.term();
+}
+
+// Like with_test_connection, but requests come from a PUSH socket of the
+// test's own, bound to a private inproc address.
+#[cfg(test)]
+fn with_pushed_connection(addr: &str, f: &fn(&mut Connection, &zmq::Socket)) {
+    let ctx = zmq::init(1).unwrap();
+    let push = ctx.socket(zmq::PUSH).unwrap();
+    push.bind(addr).unwrap();
+
+    let mut connection = connect(ctx,
+        Some(~"F0D32575-2ABB-4957-BC8B-12DAC8AFF13A"),
+        ~[addr.to_owned()],
+        ~[~"tcp://127.0.0.1:9999"]);
+
+    f(&mut connection, &push);
+
+    connection.term();
+    push.close();
+    ctx.term();
+}
+
+#[cfg(test)]
+fn sent_messages() -> ~[~[u8]] {
+    do local_data::get(sent_key) |sent| {
+        match sent {
+            Some(sent) => (**sent).clone(),
+            None => ~[],
+        }
+    }
+}
+
+// Splits an outgoing message into its sender uuid, connection ids and body.
+#[cfg(test)]
+fn split_sent(msg: &[u8]) -> (~str, ~str, ~[u8]) {
+    let space = msg.position_elem(&(' ' as u8)).unwrap();
+    let uuid = str::from_bytes(msg.slice(0u, space));
+
+    let rest = msg.slice(space + 1u, msg.len());
+    let colon = rest.position_elem(&(':' as u8)).unwrap();
+    let len = uint::from_str(str::from_bytes(rest.slice(0u, colon))).unwrap();
+    let ids = str::from_bytes(rest.slice(colon + 1u, colon + 1u + len));
+    let body = rest.slice(colon + len + 3u, rest.len()).to_owned();
+
+    (uuid, ids, body)
+}
+
+#[cfg(test)]
+fn sent_replies() -> ~[~str] {
+    do sent_messages().map |msg| {
+        let (_, _, body) = split_sent(*msg);
+        str::from_bytes(body)
+    }
+}
+
+#[test]
+fn test_if_match() {
+    let request = test_request("/", [("if-match", "\"v1\", \"v2\"")], []);
+
+    assert!(request.if_match() == Some(~[~"\"v1\"", ~"\"v2\""]));
+    assert!(request.check_if_match("\"v2\""));
+    assert!(!request.check_if_match("\"stale\""));
+
+    let request = test_request("/", [], []);
+    assert!(request.if_match() == None);
+    assert!(request.check_if_match("\"v1\""));
+}
+
+#[test]
+fn test_reply_precondition_failed() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [], []);
+        connection.reply_precondition_failed(&request).unwrap();
+
+        let replies = sent_replies();
+        assert!(replies.len() == 1u);
+        assert!(replies[0u].starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+    }
+}
+
+#[test]
+fn test_reply_status() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [], []);
+        connection.reply_status(&request, 204u, Headers()).unwrap();
+        connection.reply_status(&request, 404u, Headers()).unwrap();
+
+        let replies = sent_replies();
+        assert!(replies[0u] == ~"HTTP/1.1 204 No Content\r\n\r\n");
+        assert!(replies[1u] ==
+            ~"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+    }
+}
+
+#[test]
+fn test_buffer_pool() {
+    do with_test_connection |connection| {
+        let msg = str::to_bytes("abCD-123 56 / 12:3:foo,3:bar,}11:hello world,");
+        assert!(parse_view(msg).is_ok());
+
+        connection.enable_buffer_pool(1u);
+        for _ in range(0u, 3u) {
+            let request = connection.parse_message(msg).unwrap();
+            assert!(request.body == str::to_bytes("hello world"));
+            connection.recycle(request);
+        }
+
+        assert!(connection.buffer_reuses() == 2u);
+    }
+}
+
+#[test]
+fn test_parse_view() {
+    let mut map = HashMap::new();
+    for i in range(0u, 100u) {
+        map.insert(str::to_bytes(fmt!("x-header-%u", i)),
+                   tnetstring::Str(str::to_bytes(fmt!("value %u", i))));
+    }
+
+    let mut msg = str::to_bytes("abCD-123 56 / ");
+    msg.push_all(tnetstring::to_bytes(&tnetstring::Map(map)));
+    msg.push_all(str::to_bytes("11:hello world,"));
+
+    let request = parse(msg).unwrap();
+    let view = parse_view(msg).unwrap();
+
+    assert!(view.uuid == request.uuid);
+    assert!(view.id == request.id);
+    assert!(view.path == request.path);
+    assert!(view.body == request.body.as_slice());
+    assert!(view.headers.len() == request.headers.len());
+
+    for (key, values) in request.headers.iter() {
+        assert!(view.header_values(*key) ==
+                values.map(|value| value.as_slice()));
+    }
+}
+
+#[test]
+fn test_parse_fast_path() {
+    let mut map = HashMap::new();
+    map.insert(str::to_bytes("METHOD"), tnetstring::Str(str::to_bytes("GET")));
+    map.insert(str::to_bytes("accept"), tnetstring::Vec(~[
+        tnetstring::Str(str::to_bytes("text/html")),
+        tnetstring::Str(str::to_bytes("text/plain")),
+    ]));
+
+    let mut msg = str::to_bytes("abCD-123 56 / ");
+    msg.push_all(tnetstring::to_bytes(&tnetstring::Map(map)));
+    msg.push_all(str::to_bytes("11:hello world,"));
+
+    let fast = parse(msg).unwrap();
+    let general = io::with_bytes_reader(msg, |rdr| parse_reader(rdr, None, None)).unwrap();
+
+    assert!(fast.uuid == general.uuid);
+    assert!(fast.id == general.id);
+    assert!(fast.path == general.path);
+    assert!(fast.headers == general.headers);
+    assert!(fast.body == general.body);
+}
+
+#[test]
+fn test_flow_control() {
+    let request = parse(str::to_bytes(
+        "abCD-123 56 @* 17:{\"METHOD\":\"JSON\"},34:{\"type\":\"credits\",\"credits\":16384},"
+    )).unwrap();
+
+    assert!(request.is_flow_control());
+    assert!(!request.is_disconnect());
+    assert!(request.credits() == Some(16384u));
+
+    let request = test_request("/", [], []);
+    assert!(!request.is_flow_control());
+    assert!(request.credits() == None);
+}
+
+#[test]
+fn test_cache_control() {
+    let cc = CacheControl().public().max_age(3600u);
+    assert!(cc.to_header_value() == ~"public, max-age=3600");
+
+    let mut headers = Headers();
+    with_cache_control(&mut headers, &CacheControl().no_store());
+    assert!(headers.find(&~"Cache-Control") == Some(&~[~"no-store"]));
+}
+
+#[test]
+fn test_bearer_token() {
+    let request = test_request("/", [("authorization", "Bearer xyz.123")], []);
+    assert!(request.bearer_token() == Some(~"xyz.123"));
+
+    let request = test_request("/", [("authorization", "Basic Zm9vOmJhcg==")], []);
+    assert!(request.bearer_token() == None);
+
+    let request = test_request("/", [("authorization", "Basic é=abc")], []);
+    assert!(request.bearer_token() == None);
+
+    let request = test_request("/", [], []);
+    assert!(request.bearer_token() == None);
+}
+
+#[test]
+fn test_etag_with() {
+    let body = str::to_bytes("hello world");
+
+    let weak = etag_with(body, EtagFnv1a);
+    let strong = etag_with(body, EtagSha1);
+
+    assert!(weak != strong);
+    assert!(weak == ~"W/\"779a65e7023cd2e7\"");
+    assert!(strong == ~"\"2aae6c35c94fcfb415dbe95f408b9ce91ee846ed\"");
+    assert!(etag(body) == strong);
+}
+
+#[test]
+fn test_path_limit() {
+    do with_test_connection |connection| {
+        connection.set_path_limit("/expensive", 2u);
+
+        let now = 5000000000u64;
+        for _ in range(0u, 2u) {
+            assert!(!connection.throttle_at("/expensive/report", now));
+        }
+        assert!(connection.throttle_at("/expensive/report", now));
+
+        for _ in range(0u, 10u) {
+            assert!(!connection.throttle_at("/cheap", now));
+        }
+        for _ in range(0u, 10u) {
+            assert!(!connection.throttle_at("/expensiveish", now));
+        }
+
+        // A new window resets the count.
+        assert!(!connection.throttle_at("/expensive", now + 1000000000u64));
+    }
+}
+
+#[test]
+fn test_path_limit_replies_429() {
+    do with_test_connection |connection| {
+        connection.set_path_limit("/expensive", 1u);
+
+        let msg = str::to_bytes("abCD-123 56 /expensive 2:{},0:,");
+        assert!(connection.accept(msg).unwrap().is_some());
+        assert!(connection.accept(msg).unwrap().is_none());
+
+        let replies = sent_replies();
+        assert!(replies.len() == 1u);
+        assert!(replies[0u].starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    }
+}
+
+#[test]
+fn test_path_limit_with_ordered_replies() {
+    do with_test_connection |connection| {
+        connection.enable_ordered_replies();
+        connection.set_path_limit("/expensive", 1u);
+
+        let msg = str::to_bytes("abCD-123 56 /expensive 2:{},0:,");
+        let first = connection.accept(msg).unwrap().unwrap();
+        assert!(connection.accept(msg).unwrap().is_none());
+        assert!(sent_replies().len() == 0u);
+
+        connection.reply(&first, str::to_bytes("first")).unwrap();
+        let replies = sent_replies();
+        assert!(replies.len() == 2u);
+        assert!(replies[0u] == ~"first");
+        assert!(replies[1u].starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    }
+}
+
+#[test]
+fn test_ordered_replies_forget_disconnected() {
+    do with_test_connection |connection| {
+        connection.enable_ordered_replies();
+
+        assert!(connection.accept(
+            str::to_bytes("abCD-123 56 /first 2:{},0:,")).unwrap().is_some());
+        assert!(connection.accept(str::to_bytes(
+            "abCD-123 56 @* 17:{\"METHOD\":\"JSON\"},21:{\"type\":\"disconnect\"},"))
+            .unwrap().is_some());
+
+        let ordered = connection.ordered_replies.unwrap();
+        assert!(ordered.pending.is_empty());
+    }
+}
+
+#[test]
+fn test_fetch_metadata() {
+    let request = test_request("/", [
+        ("sec-fetch-site", "same-origin"),
+        ("sec-fetch-mode", "navigate"),
+    ], []);
+
+    assert!(request.fetch_site() == Some(~"same-origin"));
+    assert!(request.fetch_mode() == Some(~"navigate"));
+    assert!(request.fetch_dest() == None);
+}
+
+#[test]
+fn test_reply_http_stream() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [], []);
+        let mut chunks = ~[str::to_bytes(" world"), ~[], str::to_bytes("hello")];
+
+        connection.reply_http_stream(&request, 200u, "OK", Headers(), || {
+            if chunks.is_empty() { None } else { Some(chunks.pop()) }
+        }).unwrap();
+
+        let replies = sent_replies();
+        assert!(replies.len() == 4u);
+        assert!(replies[0u] ==
+            ~"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert!(replies.slice(1u, 4u).concat() ==
+            ~"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n");
+    }
+}
+
+#[test]
+fn test_reply_http_stream_http10() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [("VERSION", "HTTP/1.0")], []);
+        let mut chunks = ~[str::to_bytes(" world"), str::to_bytes("hello")];
+
+        connection.reply_http_stream(&request, 200u, "OK", Headers(), || {
+            if chunks.is_empty() { None } else { Some(chunks.pop()) }
+        }).unwrap();
+
+        assert!(sent_replies() == ~[
+            ~"HTTP/1.0 200 OK\r\nConnection: close\r\n\r\n",
+            ~"hello",
+            ~" world",
+            ~"",
+        ]);
+    }
+}
+
+#[test]
+fn test_jwt_claims() {
+    let token = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+                 eyJzdWIiOiIxMjM0NTY3ODkwIiwibmFtZSI6IkpvaG4gRG9lIiwiaWF0IjoxNTE2MjM5MDIyfQ.\
+                 SflKxwRJSMeKKF2QT4fwpMeJf36POk6yJV_adQssw5c";
+
+    match jwt_claims(token) {
+        Some(json::Object(claims)) => {
+            assert!(claims.find(&~"name") == Some(&json::String(~"John Doe")));
+            assert!(claims.find(&~"sub") == Some(&json::String(~"1234567890")));
+        }
+        _ => fail!(~"expected a claims object"),
+    }
+
+    assert!(jwt_claims("not-a-jwt").is_none());
+    assert!(jwt_claims("a.!!!.c").is_none());
+}
+
+#[test]
+fn test_ordered_replies() {
+    do with_test_connection |connection| {
+        connection.enable_ordered_replies();
+
+        let first = connection.accept(
+            str::to_bytes("abCD-123 56 /first 2:{},0:,")).unwrap().unwrap();
+        let second = connection.accept(
+            str::to_bytes("abCD-123 56 /second 2:{},0:,")).unwrap().unwrap();
+
+        connection.reply(&second, str::to_bytes("second")).unwrap();
+        assert!(sent_replies().len() == 0u);
+
+        connection.reply(&first, str::to_bytes("first")).unwrap();
+        assert!(sent_replies() == ~[~"first", ~"second"]);
+    }
+}
+
+#[test]
+fn test_ordered_reply_parts() {
+    do with_test_connection |connection| {
+        connection.enable_ordered_replies();
+
+        let first = connection.accept(
+            str::to_bytes("abCD-123 56 /first 2:{},0:,")).unwrap().unwrap();
+        let second = connection.accept(
+            str::to_bytes("abCD-123 56 /second 2:{},0:,")).unwrap().unwrap();
+
+        connection.reply_part(&second, str::to_bytes("s1")).unwrap();
+        connection.reply(&second, str::to_bytes("s2")).unwrap();
+        assert!(sent_replies().len() == 0u);
+
+        connection.reply_part(&first, str::to_bytes("f1")).unwrap();
+        assert!(sent_replies() == ~[~"f1"]);
+
+        connection.reply(&first, str::to_bytes("f2")).unwrap();
+        assert!(sent_replies() == ~[~"f1", ~"f2", ~"s1", ~"s2"]);
+    }
+}
+
+#[test]
+fn test_ack_upload_chunk() {
+    do with_test_connection |connection| {
+        let request = test_request("/upload", [], []);
+        connection.ack_upload_chunk(&request, 65536u).unwrap();
+
+        assert!(sent_replies() ==
+            ~[~"HTTP/1.1 100 Continue\r\nUpload-Offset: 65536\r\n\r\n"]);
+    }
+}
+
+#[test]
+fn test_reply_by_token() {
+    do with_test_connection |connection| {
+        let token = {
+            let request = test_request("/", [], []);
+            connection.stash_reply(&request)
+        };
+
+        connection.reply_by_token(token, str::to_bytes("done")).unwrap();
+        assert!(connection.reply_by_token(token, str::to_bytes("again")).is_err());
+
+        let sent = sent_messages();
+        assert!(sent.len() == 1u);
+        assert!(split_sent(sent[0u]) ==
+            (~"abCD-123", ~"56", str::to_bytes("done")));
+    }
+}
+
+#[test]
+fn test_effective_length() {
+    let request = parse(str::to_bytes(
+        "abCD-123 56 / 31:{\"transfer-encoding\":\"chunked\"},\
+         26:5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n,"
+    )).unwrap();
+
+    assert!(request.body == str::to_bytes("hello world"));
+    assert!(request.effective_length() == 11u);
+
+    let request = parse(str::to_bytes(
+        "abCD-123 56 / 31:{\"Transfer-Encoding\":\"Chunked\"},\
+         26:5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n,"
+    )).unwrap();
+
+    assert!(request.body == str::to_bytes("hello world"));
+    assert!(request.effective_length() == 11u);
+}
+
+#[test]
+fn test_new_session_token() {
+    let a = new_session_token(32u);
+    let b = new_session_token(32u);
+
+    assert!(a != b);
+    assert!(a.len() == 43u);
+    assert!(base64_decode(a).unwrap().len() == 32u);
+    assert!(a.iter().all(|ch| ch.is_alphanumeric() || ch == '-' || ch == '_'));
+}
+
+#[test]
+fn test_base64() {
+    assert!(base64_encode(str::to_bytes("hello"), false) == ~"aGVsbG8=");
+    assert!(base64_encode([0xfbu8, 0xffu8], true) == ~"-_8");
+    assert!(base64_decode("aGVsbG8=") == Some(str::to_bytes("hello")));
+}
+
+#[test]
+fn test_if_unmodified_since() {
+    let request = test_request("/", [
+        ("if-unmodified-since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+    ], []);
+
+    assert!(request.if_unmodified_since() == Some(784111777i64));
+    assert!(request.check_if_unmodified(784111777i64));
+    assert!(!request.check_if_unmodified(784111778i64));
+
+    let request = test_request("/", [], []);
+    assert!(request.check_if_unmodified(784111778i64));
+}
+
+#[test]
+fn test_header_case() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [], []);
+        let mut headers = Headers();
+        set_header(&mut headers, "x-custom", ~"1");
+
+        connection.set_header_case(TitleCase);
+        connection.reply_http(&request, 200u, "OK", headers.clone(), ~"").unwrap();
+
+        connection.set_header_case(LowerCase);
+        connection.reply_http(&request, 200u, "OK", headers, ~"").unwrap();
+
+        let replies = sent_replies();
+        assert!(replies[0u] ==
+            ~"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nX-Custom: 1\r\n\r\n");
+        assert!(replies[1u] ==
+            ~"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nx-custom: 1\r\n\r\n");
+    }
+
+    assert!(header_name("\u00e9tag-x", TitleCase) == ~"\u00e9tag-X");
+}
+
+#[test]
+fn test_with_content_language() {
+    let mut headers = Headers();
+    with_content_language(&mut headers, [~"en", ~"fr"]);
+    assert!(headers.find(&~"Content-Language") == Some(&~[~"en, fr"]));
+}
+
+#[test]
+fn test_is_prefetch() {
+    let request = test_request("/", [("sec-purpose", "prefetch")], []);
+    assert!(request.is_prefetch());
+
+    let request = test_request("/", [("purpose", "prefetch")], []);
+    assert!(request.is_prefetch());
+
+    let request = test_request("/", [("sec-purpose", "prefetch;prerender")], []);
+    assert!(request.is_prefetch());
+
+    let request = test_request("/", [], []);
+    assert!(!request.is_prefetch());
+}
+
+#[test]
+fn test_reply_request_timeout() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [], []);
+        connection.reply_request_timeout(&request).unwrap();
+
+        assert!(sent_replies() == ~[~"HTTP/1.1 408 Request Timeout\r\n\
+            Content-Length: 0\r\nConnection: close\r\n\r\n"]);
+    }
+}
+
+#[test]
+fn test_ws_reassembler() {
+    let mut reassembler = WsReassembler();
+
+    // A masked, unfinished text frame carrying "Hel".
+    let (frame, len) = decode_ws_frame(
+        [0x01u8, 0x83u8, 0x01u8, 0x02u8, 0x03u8, 0x04u8,
+         'H' as u8 ^ 0x01u8, 'e' as u8 ^ 0x02u8, 'l' as u8 ^ 0x03u8]
+    ).unwrap();
+    assert!(len == 9u);
+    assert!(frame == WsFrame {
+        fin: false, compressed: false, opcode: WS_TEXT, payload: str::to_bytes("Hel")
+    });
+    assert!(reassembler.push(frame).unwrap().is_none());
+
+    // A ping in the middle of the message is handed back on its own.
+    let (ping, _) = decode_ws_frame([0x89u8, 0x00u8]).unwrap();
+    assert!(reassembler.push(ping).unwrap() ==
+        Some(WsMessage { opcode: WS_PING, payload: ~[] }));
+
+    let (frame, _) = decode_ws_frame([0x80u8, 0x02u8, 'l' as u8, 'o' as u8]).unwrap();
+    assert!(reassembler.push(frame).unwrap() ==
+        Some(WsMessage { opcode: WS_TEXT, payload: str::to_bytes("Hello") }));
+}
+
+#[test]
+fn test_encode_ws_frame() {
+    let frame = WsFrame {
+        fin: true, compressed: false, opcode: WS_TEXT, payload: str::to_bytes("hi")
+    };
+
+    let server = encode_ws_frame(&frame, WsServer);
+    assert!(server == ~[0x81u8, 0x02u8, 'h' as u8, 'i' as u8]);
+    assert!(server[1u] & 0x80u8 == 0u8);
+
+    let client = encode_ws_frame(&frame, WsClient([1u8, 2u8, 3u8, 4u8]));
+    assert!(client[1u] & 0x80u8 != 0u8);
+    assert!(decode_ws_frame(client).unwrap() == (frame.clone(), 8u));
+
+    let big = WsFrame {
+        fin: true, compressed: false, opcode: WS_BINARY, payload: vec::from_elem(70000u, 7u8)
+    };
+    let encoded = encode_ws_frame(&big, WsServer);
+    assert!(encoded.len() == 70010u);
+    assert!(decode_ws_frame(encoded).unwrap() == (big, 70010u));
+}
+
+#[test]
+fn test_ws_invalid_utf8() {
+    let mut reassembler = WsReassembler();
+
+    let (frame, _) = decode_ws_frame([0x81u8, 0x02u8, 0xc3u8, 0x28u8]).unwrap();
+    match reassembler.push(frame) {
+        Err(e) => assert!(e.code == WS_CLOSE_INVALID_PAYLOAD),
+        Ok(_) => fail!(~"expected invalid UTF-8 to be rejected"),
+    }
+
+    // A character split across two fragments is fine.
+    let (frame, _) = decode_ws_frame([0x01u8, 0x01u8, 0xc3u8]).unwrap();
+    assert!(reassembler.push(frame).unwrap().is_none());
+    let (frame, _) = decode_ws_frame([0x80u8, 0x01u8, 0xa9u8]).unwrap();
+    assert!(reassembler.push(frame).unwrap().unwrap().payload == ~[0xc3u8, 0xa9u8]);
+}
+
+#[test]
+fn test_with_retry_after_date() {
+    let mut headers = Headers();
+    with_retry_after_date(&mut headers, 784111777i64);
+
+    let value = headers.find(&~"Retry-After").unwrap()[0u].clone();
+    assert!(value == ~"Sun, 06 Nov 1994 08:49:37 GMT");
+    assert!(parse_http_date(value) == Some(784111777i64));
+
+    with_retry_after(&mut headers, 120u);
+    assert!(headers.find(&~"Retry-After") == Some(&~[~"120"]));
+}
+
+#[test]
+fn test_wire_size() {
+    let msg = str::to_bytes("abCD-123 56 / 13:{\"foo\":\"bar\"},11:hello world,");
+    let request = parse(msg).unwrap();
+    assert!(request.wire_size() == msg.len());
+}
+
+#[test]
+fn test_response_builder() {
+    let mut map = ~HashMap::new();
+    map.insert(~"id", json::Number(7f));
+
+    let resp = response::ok().json(&json::Object(map)).status(201u).build();
+    let expected = ~"HTTP/1.1 201 Created\r\nContent-Length: 8\r\n\
+                     Content-Type: application/json\r\n\r\n{\"id\":7}";
+    assert!(resp.to_bytes() == str::to_bytes(expected));
+
+    do with_test_connection |connection| {
+        let request = test_request("/", [], []);
+        connection.reply_response(&request, &resp).unwrap();
+        assert!(sent_replies() == ~[expected.clone()]);
+    }
+
+    let resp = response::ok().header("X-A", "1").header("X-A", "2").text("hi").build();
+    assert!(resp.code == 200u);
+    assert!(resp.headers.find(&~"X-A") == Some(&~[~"1", ~"2"]));
+    assert!(resp.body == str::to_bytes("hi"));
+}
+
+#[test]
+fn test_control_command() {
+    assert!(control_command("status", [(~"what", ~"net")]) ==
+            str::to_bytes("26:6:status,13:4:what,3:net,}]"));
+    assert!(control_command("kill", [(~"id", ~"3")]) ==
+            str::to_bytes("19:4:kill,9:2:id,1:3,}]"));
+
+    let ctx = zmq::init(1).unwrap();
+    let mut control = connect_control(ctx, ~"tcp://127.0.0.1:9997");
+    control.term();
+    ctx.term();
+}
+
+#[test]
+fn test_multipart_streaming() {
+    let data = vec::from_elem(100000u, 'x' as u8);
+
+    let mut body = str::to_bytes("--XyZ\r\n\
+        Content-Disposition: form-data; name=\"upload\"; filename=\"big.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n");
+    body.push_all(data);
+    body.push_all(str::to_bytes("\r\n--XyZ--\r\n"));
+
+    let request = test_request("/upload", [
+        ("content-type", "multipart/form-data; boundary=XyZ"),
+    ], body);
+
+    let mut parts = 0u;
+    let result = do request.multipart_streaming |headers, rdr| {
+        parts += 1u;
+        assert!(headers.find(&~"content-type") == Some(&~[~"text/plain"]));
+        assert!(rdr.read_whole_stream() == data);
+    };
+
+    assert!(result.is_ok());
+    assert!(parts == 1u);
+
+    let request = test_request("/upload", [
+        ("content-type", "multipart/form-data; boundaryé=XyZ"),
+    ], []);
+    assert!(request.multipart_streaming(|_, _| { }).is_err());
+}
+
+#[test]
+fn test_hmac() {
+    let mac = hmac(&mut Sha256::new(), str::to_bytes("key"),
+                   str::to_bytes("The quick brown fox jumps over the lazy dog"));
+    assert!(to_hex(mac) ==
+        ~"f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+}
+
+#[test]
+fn test_csrf_token() {
+    let secret = str::to_bytes("s3cr3t");
+    let token = csrf_token("session-1", secret);
+
+    assert!(verify_csrf(token, "session-1", secret));
+    assert!(!verify_csrf(token, "session-2", secret));
+
+    let mut tampered = token.clone();
+    tampered.pop_char();
+    tampered.push_char(if token.ends_with("A") { 'B' } else { 'A' });
+    assert!(!verify_csrf(tampered, "session-1", secret));
+}
+
+#[test]
+fn test_reply_http_version() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [("VERSION", "HTTP/1.0")], []);
+        assert!(request.http_version() == ~"HTTP/1.0");
+        connection.reply_http(&request, 200u, "OK", Headers(), ~"hi").unwrap();
+
+        let request = test_request("/", [], []);
+        connection.reply_http(&request, 200u, "OK", Headers(), ~"hi").unwrap();
+
+        let replies = sent_replies();
+        assert!(replies[0u].starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(replies[1u].starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
+
+#[test]
+fn test_chunked_body_limit() {
+    // Ten one byte chunks, with the stream cut off before the last chunk.
+    let mut body = ~"";
+    for _ in range(0u, 10u) { body.push_str("1\r\nx\r\n"); }
+
+    assert!(dechunk(str::to_bytes(body), Some(5u)) ==
+            Err(~"chunked body exceeds maximum size"));
+    assert!(dechunk(str::to_bytes(body), None) == Err(~"truncated chunk size"));
+
+    do with_test_connection |connection| {
+        connection.set_max_body_size(5u);
+
+        let msg = str::to_bytes(
+            "abCD-123 56 / 31:{\"transfer-encoding\":\"chunked\"},\
+             26:1\r\na\r\n1\r\nb\r\n4\r\ncdef\r\n0\r\n\r\n,");
+        assert!(connection.parse_message(msg).unwrap_err() ==
+                ~"chunked body exceeds maximum size");
+
+        let msg = str::to_bytes("abCD-123 56 / 2:{},6:abcdef,");
+        assert!(connection.parse_message(msg).unwrap_err() ==
+                ~"body exceeds maximum size");
+    }
+}
+
+#[test]
+fn test_dechunk_huge_size() {
+    let body = str::to_bytes("ffffffffffffffff\r\nabc\r\n0\r\n\r\n");
+    assert!(dechunk(body, None).is_err());
+    assert!(dechunk(body, Some(5u)).is_err());
+}
+
+#[test]
+fn test_transport() {
+    let ctx = zmq::init(1).unwrap();
+
+    let mut connection = connect(ctx,
+        None,
+        ~[~"ipc:///tmp/rust-mongrel2-test-req"],
+        ~[~"ipc:///tmp/rust-mongrel2-test-rep"]);
+    assert!(connection.transport() == ~"ipc");
+    connection.term();
+
+    do with_test_connection |connection| {
+        assert!(connection.transport() == ~"tcp");
+    }
+
+    ctx.term();
+}
+
+#[test]
+fn test_server_timing() {
+    let mut timing = ServerTiming();
+    timing.add("db", 53f);
+    timing.add("app", 12f);
+    assert!(timing.to_header_value() == ~"db;dur=53, app;dur=12");
+
+    let value = timing.time("render", || 42u);
+    assert!(value == 42u);
+
+    let mut headers = Headers();
+    let mut timing = ServerTiming();
+    timing.add_described("cache", 0.5f, "Cache Read").unwrap();
+    with_server_timing(&mut headers, &timing);
+    assert!(headers.find(&~"Server-Timing") ==
+            Some(&~[~"cache;desc=\"Cache Read\";dur=0.5"]));
+
+    let mut timing = ServerTiming();
+    timing.add_described("db", 2f, "say \"hi\" \\o/").unwrap();
+    assert!(timing.add_described("db", 2f, "a\r\nX-A: 1").is_err());
+    assert!(timing.to_header_value() == ~"db;desc=\"say \\\"hi\\\" \\\\o/\";dur=2");
+}
+
+#[test]
+fn test_parse_json_limited() {
+    let deep = str::to_bytes(str::from_chars(vec::from_elem(100u, '[')) +
+                             str::from_chars(vec::from_elem(100u, ']')));
+    assert!(parse_json_limited(deep, 64u, 1024u).unwrap_err() ==
+            ~"JSON is nested deeper than 64 levels");
+    assert!(parse_json_limited(deep, 100u, 1024u).is_ok());
+
+    // Brackets inside strings don't count.
+    assert!(parse_json_limited(str::to_bytes("{\"a\":\"[[[[\"}"), 1u, 1024u).is_ok());
+
+    assert!(parse_json_limited(str::to_bytes("[1,2,3]"), 64u, 4u).is_err());
+}
+
+#[test]
+fn test_reply_jsonp() {
+    let mut map = ~HashMap::new();
+    map.insert(~"ok", json::Boolean(true));
+    let value = json::Object(map);
+
+    do with_test_connection |connection| {
+        let request = test_request("/api", [("QUERY", "callback=cb&x=1")], []);
+        connection.reply_jsonp(&request, "callback", &value).unwrap();
+
+        let request = test_request("/api?callback=alert(1)//", [], []);
+        connection.reply_jsonp(&request, "callback", &value).unwrap();
+
+        let replies = sent_replies();
+        assert!(replies[0u].starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(replies[0u].contains("Content-Type: application/javascript\r\n"));
+        assert!(replies[0u].ends_with("\r\n\r\ncb({\"ok\":true})"));
+        assert!(replies[1u].starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
+
+#[test]
+fn test_url_decode() {
+    assert!(url_decode("a%20b+c%2F") == ~"a b c/");
+    assert!(url_decode("100%") == ~"100%");
+    assert!(url_decode("%zz") == ~"%zz");
+}
+
+#[test]
+fn test_declared_body_size() {
+    let msg = str::to_bytes("abCD-123 56 / 22:14:content-length,1:5,}5:hello,");
+    let view = parse_view(msg).unwrap();
+
+    assert!(view.declared_body_size() == Some(5u));
+    assert!(view.header("content-length") == Some("5"));
+    assert!(parse(msg).unwrap().body.len() == 5u);
+
+    let spooled = str::to_bytes("abCD-123 56 / 24:14:Content-Length,3:512,}0:,");
+    assert!(parse_view(spooled).unwrap().declared_body_size() == Some(512u));
+
+    let invalid = str::to_bytes("abCD-123 56 / 24:14:content-length,3:abc,}0:,");
+    assert!(parse_view(invalid).unwrap().declared_body_size() == None);
+
+    let empty = str::to_bytes("abCD-123 56 / 0:}0:,");
+    assert!(parse_view(empty).unwrap().declared_body_size() == Some(0u));
+}
+
+#[test]
+fn test_conflicting_content_length() {
+    let request = test_request("/", [
+        ("content-length", "5"),
+        ("content-length", "10"),
+    ], []);
+    assert!(request.has_conflicting_content_length());
+
+    let request = test_request("/", [("content-length", "5, 5")], []);
+    assert!(!request.has_conflicting_content_length());
+
+    let request = test_request("/", [
+        ("Content-Length", "5"),
+        ("content-length", "10"),
+    ], []);
+    assert!(request.has_conflicting_content_length());
+
+    let msg = str::to_bytes(
+        "abCD-123 56 / 29:{\"content-length\":[\"5\",\"10\"]},5:hello,");
+    assert!(parse(msg).unwrap_err() == ~"conflicting content-length headers");
+
+    do with_test_connection |connection| {
+        assert!(connection.accept(msg).unwrap_err() == ~"conflicting content-length headers");
+
+        let sent = sent_messages();
+        assert!(sent.len() == 1u);
+        let (uuid, id, body) = split_sent(sent[0u]);
+        assert!(uuid == ~"abCD-123" && id == ~"56");
+        assert!(str::from_bytes(body).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        assert!(connection.accept(str::to_bytes("garbage")).is_err());
+        assert!(sent_messages().len() == 1u);
+    }
+}
+
+#[test]
+fn test_reject_websocket() {
+    do with_test_connection |connection| {
+        let request = test_request("/ws", [
+            ("upgrade", "websocket"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ], []);
+        connection.reject_websocket(&request, 403u, "not allowed").unwrap();
+
+        let replies = sent_replies();
+        assert!(replies.len() == 1u);
+        assert!(replies[0u].starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(replies[0u].contains("Connection: close\r\n"));
+        assert!(replies[0u].ends_with("\r\n\r\nnot allowed"));
+    }
+}
+
+#[test]
+fn test_websocket_accept() {
+    assert!(websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ==") ==
+            ~"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    do with_test_connection |connection| {
+        let request = test_request("/ws", [
+            ("upgrade", "websocket"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("sec-websocket-protocol", "chat, superchat"),
+        ], []);
+
+        assert!(connection.websocket_accept(&request, [~"superchat"]).unwrap() ==
+                Some(~"superchat"));
+        assert!(connection.websocket_accept(&request, [~"mqtt"]).unwrap() == None);
+
+        let replies = sent_replies();
+        assert!(replies[0u].starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(replies[0u].contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(replies[0u].contains("Sec-WebSocket-Protocol: superchat\r\n"));
+        assert!(!replies[1u].contains("Sec-WebSocket-Protocol"));
+    }
+}
+
+#[test]
+fn test_websocket_permessage_deflate() {
+    do with_test_connection |connection| {
+        let request = test_request("/ws", [
+            ("upgrade", "websocket"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("sec-websocket-extensions",
+             "permessage-deflate; server_max_window_bits=10, permessage-deflate; client_max_window_bits"),
+        ], []);
+
+        let (_, deflate) = connection.websocket_accept_deflate(&request, []).unwrap();
+        assert!(deflate);
+
+        let replies = sent_replies();
+        assert!(replies[0u].contains("Sec-WebSocket-Extensions: permessage-deflate; \
+                                      server_no_context_takeover; client_no_context_takeover\r\n"));
+    }
+
+    let text = str::to_bytes("Hello Hello Hello Hello");
+    let frame = WsFrame { fin: true, compressed: true, opcode: WS_TEXT, payload: ws_deflate(text) };
+    let (decoded, _) = decode_ws_frame(encode_ws_frame(&frame, WsClient([1u8, 2u8, 3u8, 4u8])))
+        .unwrap();
+    assert!(decoded.compressed);
+
+    let mut reassembler = WsReassembler();
+    assert!(reassembler.push(decoded.clone()).is_err());
+
+    reassembler.enable_deflate();
+    assert!(reassembler.push(decoded).unwrap() ==
+        Some(WsMessage { opcode: WS_TEXT, payload: text }));
+}
+
+#[test]
+fn test_ws_inflate_limits() {
+    let text = str::to_bytes("abcabcabcabc".repeat(1000u));
+    assert!(ws_inflate(ws_deflate(text), WS_MAX_MESSAGE_SIZE) == Ok(text.clone()));
+
+    match ws_inflate(ws_deflate(text), 1000u) {
+        Ok(_) => fail!(),
+        Err(e) => assert!(e.code == WS_CLOSE_MESSAGE_TOO_BIG),
+    }
+
+    match ws_inflate([0xffu8, 0xffu8, 0xffu8], WS_MAX_MESSAGE_SIZE) {
+        Ok(_) => fail!(),
+        Err(e) => assert!(e.code == WS_CLOSE_INVALID_PAYLOAD),
+    }
+}
+
+#[test]
+fn test_inflate() {
+    let hello = str::to_bytes("hello");
+    let stored = [0x01u8, 0x05u8, 0x00u8, 0xfau8, 0xffu8,
+                  'h' as u8, 'e' as u8, 'l' as u8, 'l' as u8, 'o' as u8];
+    assert!(inflate::inflate(stored, 100u) == Ok(hello.clone()));
+    assert!(inflate::inflate(stored, 4u) == Err(inflate::TooLarge));
+
+    // Stored length that doesn't match its complement, and one that runs
+    // past the input.
+    let bad_nlen = [0x01u8, 0x05u8, 0x00u8, 0x00u8, 0x00u8,
+                    'h' as u8, 'e' as u8, 'l' as u8, 'l' as u8, 'o' as u8];
+    assert!(inflate::inflate(bad_nlen, 100u) == Err(inflate::Corrupt));
+    assert!(inflate::inflate(stored.slice_to(7u), 100u) == Err(inflate::Corrupt));
+
+    // Reserved block type.
+    assert!(inflate::inflate([0x07u8], 100u) == Err(inflate::Corrupt));
+
+    // "hello hello hello" as a fixed Huffman block, whole and truncated.
+    let fixed = [0xcbu8, 0x48u8, 0xcdu8, 0xc9u8, 0xc9u8, 0x57u8, 0xc8u8, 0x40u8,
+                 0x90u8, 0x00u8];
+    assert!(inflate::inflate(fixed, 100u) == Ok(str::to_bytes("hello hello hello")));
+    assert!(inflate::inflate(fixed.slice_to(7u), 100u) == Err(inflate::Corrupt));
+
+    // A dynamic Huffman block.
+    let dynamic = [0x05u8, 0xc1u8, 0x01u8, 0x01u8, 0x00u8, 0x00u8, 0x08u8, 0xc3u8,
+                   0xa0u8, 0xacu8, 0xccu8, 0xf7u8, 0xcfu8, 0x20u8, 0x00u8, 0x00u8,
+                   0x00u8, 0x00u8, 0x00u8, 0x54u8, 0xddu8, 0x1eu8];
+    let text = str::to_bytes("a".repeat(40u) + "bbbbcd");
+    assert!(inflate::inflate(dynamic, 100u) == Ok(text));
+    assert!(inflate::inflate(dynamic.slice_to(15u), 100u) == Err(inflate::Corrupt));
+
+    // A back reference before the start of the output.
+    assert!(inflate::inflate([0x03u8, 0x02u8, 0x00u8], 100u) == Err(inflate::Corrupt));
+
+    // A dynamic block whose code length code is over-subscribed.
+    assert!(inflate::inflate([0x05u8, 0x00u8, 0x92u8, 0x04u8], 100u) ==
+        Err(inflate::Corrupt));
+}
+
+#[test]
+fn test_query_pairs() {
+    let request = test_request("/search?b=2&a=1&b=3", [], []);
+    assert!(request.query_pairs() == ~[(~"b", ~"2"), (~"a", ~"1"), (~"b", ~"3")]);
+
+    let request = test_request("/search", [("QUERY", "q=hello+world")], []);
+    assert!(request.query_pairs() == ~[(~"q", ~"hello world")]);
+    assert!(test_request("/search", [], []).query_pairs().is_empty());
+}
+
+#[test]
+fn test_verify_signed_url() {
+    let secret = "download secret".as_bytes();
+    let signature = url_signature("/files/report.pdf",
+                                  [(~"user", ~"42"), (~"expires", ~"1700000000")],
+                                  secret);
+
+    let path = fmt!("/files/report.pdf?user=42&sig=%s&expires=1700000000", signature);
+    let request = test_request(path.as_slice(), [], []);
+    assert!(verify_signed_url(&request, secret, "sig"));
+    assert!(!verify_signed_url(&request, "other secret".as_bytes(), "sig"));
+
+    let path = fmt!("/files/report.pdf?user=43&sig=%s&expires=1700000000", signature);
+    let request = test_request(path.as_slice(), [], []);
+    assert!(!verify_signed_url(&request, secret, "sig"));
+
+    let request = test_request("/files/report.pdf?user=42&expires=1700000000", [], []);
+    assert!(!verify_signed_url(&request, secret, "sig"));
+}
+
+#[test]
+fn test_prefetch() {
+    do with_pushed_connection("inproc://test-prefetch") |connection, push| {
+        connection.enable_prefetch(2u);
+
+        for path in ["/one", "/two", "/three"].iter() {
+            let msg = fmt!("abCD-123 56 %s 2:{},0:,", *path);
+            push.send(msg.as_bytes(), 0).unwrap();
+        }
+
+        assert!(connection.recv().unwrap().path == ~"/one");
+        assert!(connection.recv().unwrap().path == ~"/two");
+        assert!(connection.recv().unwrap().path == ~"/three");
+    }
+}
+
+#[test]
+fn test_parse_errors_name_field() {
+    assert!(parse(str::to_bytes("abCD-123 56")).unwrap_err() ==
+            ~"invalid path: missing or truncated");
+    assert!(parse(str::to_bytes("abCD-123 56 / 4:[1],0:,")).unwrap_err() ==
+            ~"invalid headers: JSON is not a dictionary");
+    assert!(parse(str::to_bytes("abCD-123 56 / 7:{\"a\":1},0:,")).unwrap_err() ==
+            ~"invalid headers: value of a is not a string");
+    assert!(parse(str::to_bytes("abCD-123 56 / 2:{},3:abc")).unwrap_err() ==
+            ~"invalid body: missing or truncated netstring");
+}
+
+#[test]
+fn test_recv_nonblock_and_timeout() {
+    do with_test_connection |connection| {
+        assert!(connection.recv_nonblock().unwrap().is_none());
+
+        let start = time::precise_time_ns();
+        assert!(connection.recv_timeout(20).unwrap().is_none());
+        assert!(time::precise_time_ns() - start >= 20000000u64);
+    }
+}
+
+#[test]
+fn test_content_security_policy() {
+    let csp = ContentSecurityPolicy()
+        .directive("default-src", ["'self'"])
+        .directive("script-src", ["'self'"])
+        .directive("script-src", ["cdn.example.com", "'self'"]);
+    assert!(csp.to_header_value() == ~"default-src 'self'; script-src 'self' cdn.example.com");
+
+    let mut headers = Headers();
+    with_content_security_policy(&mut headers, &csp.directive("upgrade-insecure-requests", []));
+    assert!(headers.find(&~"Content-Security-Policy") == Some(&~[
+        ~"default-src 'self'; script-src 'self' cdn.example.com; upgrade-insecure-requests"
+    ]));
+}
+
+#[test]
+fn test_echo() {
+    do with_test_connection |connection| {
+        let request = test_request("/echo", [("content-type", "text/plain")],
+                                   str::to_bytes("ping"));
+        connection.echo(&request).unwrap();
+
+        let replies = sent_replies();
+        assert!(replies[0u].starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(replies[0u].contains("Content-Type: text/plain\r\n"));
+        assert!(replies[0u].ends_with("\r\n\r\nping"));
+    }
+}
+
+#[test]
+fn test_query() {
+    let request = test_request("/search?q=rust+lang&tag=a%26b&tag=c&flag&", [], []);
+    let query = request.query();
+
+    assert!(query.len() == 3u);
+    assert!(query.find(&~"q") == Some(&~[~"rust lang"]));
+    assert!(query.find(&~"tag") == Some(&~[~"a&b", ~"c"]));
+    assert!(query.find(&~"flag") == Some(&~[~""]));
+    assert!(request.path_only() == ~"/search");
+    assert!(test_request("/search", [], []).path_only() == ~"/search");
+}
+
+#[test]
+fn test_max_forwards() {
+    assert!(test_request("/", [("max-forwards", "0")], []).max_forwards() == Some(0));
+    assert!(test_request("/", [("max-forwards", " 10 ")], []).max_forwards() == Some(10));
+    assert!(test_request("/", [("max-forwards", "-1")], []).max_forwards() == None);
+    assert!(test_request("/", [], []).max_forwards() == None);
+}
+
+#[test]
+fn test_send_batches_ids() {
+    do with_test_connection |connection| {
+        let ids = vec::from_fn(300u, |i| uint::to_str(i));
+        connection.send("abCD-123", ids, str::to_bytes("hello")).unwrap();
+
+        let sent = sent_messages();
+        assert!(sent.len() == 3u);
+
+        let mut received = ~[];
+        for msg in sent.iter() {
+            let (uuid, ids, body) = split_sent(*msg);
+            assert!(uuid == ~"abCD-123");
+            assert!(body == str::to_bytes("hello"));
+            for id in ids.split_iter(' ') { received.push(id.to_owned()); }
+        }
+        assert!(received == ids);
+    }
+}
+
+#[test]
+fn test_reply_batch() {
+    do with_test_connection |connection| {
+        let ids = ~[~"1", ~"2", ~"3", ~"4", ~"5"];
+        connection.reply_batch("abCD-123", ids, str::to_bytes("hello")).unwrap();
+
+        let sent = sent_messages();
+        assert!(sent.len() == 1u);
+        let (_, sent_ids, body) = split_sent(sent[0u]);
+        assert!(sent_ids == ~"1 2 3 4 5");
+        assert!(body == str::to_bytes("hello"));
+
+        let too_many = vec::from_fn(MAX_IDS_PER_MESSAGE + 1u, |i| uint::to_str(i));
+        assert!(connection.reply_batch("abCD-123", too_many, []).is_err());
+        assert!(sent_messages().len() == 1u);
+    }
+}
+
+#[test]
+fn test_header_ignores_case() {
+    let request = test_request("/", [("Connection", "close"), ("X-Token", "abc")], []);
+    assert!(request.should_close());
+    assert!(request.header("x-token") == Some(~[~"abc"]));
+    assert!(request.header_first("X-TOKEN") == Some(~"abc"));
+    assert!(request.header("x-missing") == None);
+    assert!(request.headers.contains_key(&~"X-Token"));
+
+    let request = test_request("/", [("connection", "keep-alive"), ("VERSION", "HTTP/1.1")], []);
+    assert!(!request.should_close());
+
+    let request = test_request("/", [("x-tag", "b"), ("X-Tag", "a"), ("X-TAG", "c")], []);
+    assert!(request.header("x-tag") == Some(~[~"c", ~"a", ~"b"]));
+    assert!(request.header_first("x-tag") == Some(~"c"));
+}
+
+#[test]
+fn test_with_age() {
+    let mut headers = Headers();
+    with_age(&mut headers, 42u);
+    with_cache_hit(&mut headers);
+    assert!(headers.find(&~"Age") == Some(&~[~"42"]));
+    assert!(headers.find(&~"X-Cache") == Some(&~[~"HIT"]));
+
+    with_cache_miss(&mut headers);
+    assert!(headers.find(&~"X-Cache") == Some(&~[~"MISS"]));
+
+    let head = str::from_bytes(http_head("HTTP/1.1", 200u, "OK", &headers, Some(0u),
+                                         PreserveCase, false));
+    assert!(head.contains("Age: 42\r\n"));
+}
+
+#[test]
+fn test_reply_http_chunks() {
+    do with_test_connection |connection| {
+        let request = test_request("/download", [], []);
+
+        connection.reply_http_start(&request, 200u, "OK", Headers()).unwrap();
+        connection.reply_http_chunk(&request, str::to_bytes("hello world")).unwrap();
+        connection.reply_http_chunk(&request, []).unwrap();
+        connection.reply_http_chunk(&request, str::to_bytes("!")).unwrap();
+        connection.reply_http_end(&request).unwrap();
+
+        let replies = sent_replies();
+        assert!(replies.len() == 4u);
+        assert!(replies[0u].contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!replies[0u].contains("Content-Length"));
+        assert!(replies[1u] == ~"b\r\nhello world\r\n");
+        assert!(replies[2u] == ~"1\r\n!\r\n");
+        assert!(replies[3u] == ~"0\r\n\r\n");
+    }
+}
+
+#[test]
+fn test_accept_charset() {
+    let request = test_request("/", [("accept-charset", "utf-8, iso-8859-1;q=0.5")], []);
+    assert!(request.accept_charset() == ~[(~"utf-8", 1f64), (~"iso-8859-1", 0.5f64)]);
+    assert!(request.prefers_charset([~"iso-8859-1", ~"UTF-8"]) == Some(~"UTF-8"));
+    assert!(request.prefers_charset([~"iso-8859-1"]) == Some(~"iso-8859-1"));
+    assert!(request.prefers_charset([~"koi8-r"]) == None);
+
+    let request = test_request("/", [("accept-charset", "koi8-r;q=0, *;q=0.1")], []);
+    assert!(request.prefers_charset([~"koi8-r", ~"utf-8"]) == Some(~"utf-8"));
+
+    let request = test_request("/", [], []);
+    assert!(request.prefers_charset([~"utf-8"]) == Some(~"utf-8"));
+
+    let request = test_request("/", [("accept-charset", "utf-8;qé")], []);
+    assert!(request.accept_charset() == ~[(~"utf-8", 1f64)]);
+    let request = test_request("/", [("accept-encoding", "gzip;qé")], []);
+    assert!(request.accepts_encoding("gzip"));
+}
+
+#[test]
+fn test_disconnect() {
+    let request = parse(str::to_bytes(
+        "abCD-123 56 @* 17:{\"METHOD\":\"JSON\"},21:{\"type\":\"disconnect\"},")).unwrap();
+    assert!(request.is_disconnect());
+
+    assert!(test_request("@*", [("METHOD", "JSON")], []).is_disconnect());
+    assert!(!test_request("@*", [], []).is_disconnect());
+    assert!(!test_request("/", [("METHOD", "GET")], []).is_disconnect());
+
+    do with_test_connection |connection| {
+        connection.close_connection(&request).unwrap();
+
+        let (uuid, ids, body) = split_sent(sent_messages()[0u]);
+        assert!(uuid == ~"abCD-123");
+        assert!(ids == ~"56");
+        assert!(body.is_empty());
+    }
+}
+
+#[test]
+fn test_release_body() {
+    let mut request = test_request("/upload", [("content-type", "text/plain")],
+                                   vec::from_elem(4096u, 1u8));
+    request.release_body();
+
+    assert!(request.body.is_empty());
+    assert!(request.path == ~"/upload");
+    assert!(request.header_first("content-type") == Some(~"text/plain"));
+}
+
+#[test]
+fn test_reply_http_compressed() {
+    assert!(crc32("123456789".as_bytes()) == 0xcbf43926u32);
+
+    do with_test_connection |connection| {
+        let body = str::from_bytes(vec::from_elem(300u, 'a' as u8));
+        let gzip_request = test_request("/", [("accept-encoding", "deflate, gzip")], []);
+        let plain_request = test_request("/", [("accept-encoding", "gzip;q=0")], []);
+
+        connection.reply_http_compressed(&gzip_request, 200u, "OK", Headers(),
+                                         body.clone()).unwrap();
+        connection.reply_http_compressed(&plain_request, 200u, "OK", Headers(),
+                                         body.clone()).unwrap();
+        connection.reply_http_compressed(&gzip_request, 200u, "OK", Headers(),
+                                         ~"tiny").unwrap();
+
+        let mut headers = Headers();
+        set_header(&mut headers, "Content-Encoding", ~"br");
+        connection.reply_http_compressed(&gzip_request, 200u, "OK", headers,
+                                         body.clone()).unwrap();
+
+        let replies = do sent_messages().map |msg| {
+            let (_, _, reply) = split_sent(*msg);
+            let end = find_bytes(reply, "\r\n\r\n".as_bytes()).unwrap() + 4u;
+            (str::from_bytes(reply.slice_to(end)), reply.slice_from(end).to_owned())
+        };
 
-        match value {
-            &json::String(ref v) => values.push(v.clone()),
-            &json::List(ref vs) => {
-                for v in vs.iter() {
-                    match v {
-                        &json::String(ref v) => values.push(v.clone()),
-                        _ => return Err(~"header value is not a string"),
-                    }
-                }
-            }
-            _ => return Err(~"header value is not string"),
-        }
+        let (ref head, ref payload) = replies[0u];
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(head.contains("Vary: Accept-Encoding\r\n"));
+        assert!(head.contains(fmt!("Content-Length: %u\r\n", payload.len())));
+        assert!(bytes_start_with(*payload, [0x1fu8, 0x8bu8]));
+        assert!(flate::inflate_bytes(payload.slice(10u, payload.len() - 8u)) ==
+                str::to_bytes(body));
 
-        headers.insert(key.clone(), values);
+        let (ref head, ref payload) = replies[1u];
+        assert!(!head.contains("Content-Encoding"));
+        assert!(*payload == str::to_bytes(body));
+
+        let (ref head, ref payload) = replies[2u];
+        assert!(!head.contains("Content-Encoding"));
+        assert!(*payload == str::to_bytes("tiny"));
+
+        let (ref head, ref payload) = replies[3u];
+        assert!(head.contains("Content-Encoding: br\r\n"));
+        assert!(*payload == str::to_bytes(body));
     }
+}
 
-    Ok(headers)
+#[test]
+fn test_reply_raw_http() {
+    do with_test_connection |connection| {
+        let request = test_request("/proxy", [], []);
+
+        connection.reply_raw_http(&request, "HTTP/1.1 200 OK\r\n\
+            Content-Type: text/plain\r\n\
+            Connection: keep-alive, X-Upstream\r\n\
+            Keep-Alive: timeout=5\r\n\
+            X-Upstream: 10.0.0.1\r\n\
+            Content-Length: 5\r\n\r\nhello".as_bytes()).unwrap();
+
+        connection.reply_raw_http(&request, "HTTP/1.1 404 Not Found\r\n\
+            Transfer-Encoding: chunked\r\n\
+            ETag: \"abc\"\r\n\r\n\
+            5\r\nhello\r\n0\r\n\r\n".as_bytes()).unwrap();
+
+        assert!(connection.reply_raw_http(&request, "HTTP/1.1 200 OK\r\n".as_bytes()).is_err());
+
+        assert!(sent_replies() == ~[
+            ~"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+              Content-Length: 5\r\n\r\nhello",
+            ~"HTTP/1.1 404 Not Found\r\nETag: \"abc\"\r\n\
+              Content-Length: 5\r\n\r\nhello",
+        ]);
+    }
 }
 
-fn parse_body(rdr: @io::Reader) -> Result<~[u8], ~str> {
-    match tnetstring::from_reader(rdr) {
-        None => Err(~"empty body"),
-        Some(tns) => {
-            match tns {
-                tnetstring::Str(body) => Ok(body),
-                _ => Err(~"invalid body"),
-            }
-        }
+#[test]
+fn test_request_hooks() {
+    do with_test_connection |connection| {
+        let started = @mut ~[];
+        let ended = @mut ~[];
+        connection.on_request_start(|req| started.push(req.id.clone()));
+        connection.on_request_end(|req| ended.push(req.id.clone()));
+
+        let msg = str::to_bytes("abCD-123 56 /trace 2:{},0:,");
+        let request = connection.accept(msg).unwrap().unwrap();
+        assert!(*started == ~[~"56"]);
+        assert!(ended.is_empty());
+
+        connection.ack_upload_chunk(&request, 10u).unwrap();
+        connection.reply_http_start(&request, 200u, "OK", Headers()).unwrap();
+        connection.reply_http_chunk(&request, str::to_bytes("data")).unwrap();
+        assert!(ended.is_empty());
+
+        connection.reply_http_end(&request).unwrap();
+        assert!(*ended == ~[~"56"]);
     }
 }
 
 #[test]
-fn test() {
+fn test_cookies() {
+    let request = test_request("/", [
+        ("cookie", "token=abc=def; theme=\"dark\"; token=old"),
+    ], []);
+    let cookies = request.cookies();
+    assert!(cookies.find(&~"token") == Some(&~"abc=def"));
+    assert!(cookies.find(&~"theme") == Some(&~"dark"));
+
+    let request = test_request("/", [
+        ("cookie", "$Version=1; id=42; $Path=/app; $Domain=.example.com"),
+    ], []);
+    assert!(request.request_cookies() == ~[RequestCookie {
+        name: ~"id",
+        value: ~"42",
+        path: Some(~"/app"),
+        domain: Some(~".example.com"),
+    }]);
+
+    assert!(test_request("/", [], []).cookies().is_empty());
+}
+
+#[test]
+fn test_host_allowed() {
+    let allowlist = [~"example.com", ~"[::1]"];
+
+    assert!(test_request("/", [("host", "Example.COM:8080")], []).host() == Some(~"example.com"));
+    assert!(test_request("/", [("host", "example.com.")], []).host_allowed(allowlist));
+    assert!(test_request("/", [("host", "[::1]:80")], []).host_allowed(allowlist));
+    assert!(!test_request("/", [("host", "evil.example")], []).host_allowed(allowlist));
+    assert!(!test_request("/", [], []).host_allowed(allowlist));
+
+    do with_test_connection |connection| {
+        connection.reply_bad_host(&test_request("/", [("host", "evil.example")], [])).unwrap();
+        assert!(sent_replies()[0u].starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
+
+#[test]
+fn test_send_ws_text() {
+    do with_test_connection |connection| {
+        let request = test_request("/ws", [], []);
+        connection.send_ws_text(&request, "héllo").unwrap();
+
+        let (_, ids, body) = split_sent(sent_messages()[0u]);
+        assert!(ids == ~"56");
+
+        let (frame, len) = decode_ws_frame(body).unwrap();
+        assert!(len == body.len());
+        assert!(frame.fin && frame.opcode == WS_TEXT);
+        assert!(frame.payload == str::to_bytes("héllo"));
+    }
+}
+
+#[test]
+fn test_send_ws_binary() {
+    do with_test_connection |connection| {
+        let request = test_request("/ws", [], []);
+        let data = ~[0u8, 255u8, 1u8, 128u8];
+        connection.send_ws_binary(&request, data).unwrap();
+
+        let (_, _, body) = split_sent(sent_messages()[0u]);
+        assert!(body[0u] == 0x80u8 | WS_BINARY);
+
+        let (frame, _) = decode_ws_frame(body).unwrap();
+        assert!(frame.opcode == WS_BINARY);
+        assert!(frame.payload == data);
+    }
+}
+
+#[test]
+fn test_response_size() {
+    let resp = response::status(404u)
+        .header("X-A", "1")
+        .header("X-A", "22")
+        .text("not here")
+        .build();
+    assert!(response_size(&resp) == resp.to_bytes().len());
+
+    let resp = response::ok().build();
+    assert!(response_size(&resp) == resp.to_bytes().len());
+}
+
+#[test]
+fn test_prefer() {
+    let request = test_request("/", [("prefer", "return=minimal; foo=bar"),
+                                     ("prefer", "respond-async, wait=\"10\"")], []);
+    assert!(request.prefer("return") == Some(~"minimal"));
+    assert!(request.prefer("Respond-Async") == Some(~""));
+    assert!(request.prefer("wait") == Some(~"10"));
+    assert!(request.prefer("handling") == None);
+    assert!(test_request("/", [], []).prefer("return") == None);
+}
+
+#[test]
+fn test_with_preference_applied() {
+    let mut headers = Headers();
+    with_preference_applied(&mut headers, [(~"return", ~"minimal")]);
+    assert!(headers.find(&~"Preference-Applied") == Some(&~[~"return=minimal"]));
+
+    with_preference_applied(&mut headers, [(~"return", ~"minimal"), (~"respond-async", ~"")]);
+    assert!(headers.find(&~"Preference-Applied") == Some(&~[~"return=minimal, respond-async"]));
+}
+
+#[test]
+fn test_request_no_cache() {
+    assert!(test_request("/", [("cache-control", "no-cache")], []).request_no_cache());
+    assert!(test_request("/", [("cache-control", "max-age=0, No-Cache")], []).request_no_cache());
+    assert!(test_request("/", [("pragma", "no-cache")], []).request_no_cache());
+    assert!(!test_request("/", [("cache-control", "max-age=60"), ("pragma", "no-cache")], [])
+            .request_no_cache());
+    assert!(!test_request("/", [], []).request_no_cache());
+}
+
+#[test]
+fn test_reply_misdirected() {
+    do with_test_connection |connection| {
+        let request = test_request("/", [("host", "other.example")], []);
+        connection.reply_misdirected(&request).unwrap();
+        assert!(sent_replies()[0u].starts_with("HTTP/1.1 421 Misdirected Request\r\n"));
+    }
+}
+
+#[test]
+fn test_dechunk_extensions() {
+    let body = "5;name=value\r\nhello\r\n6 ; last\r\n world\r\n0;end\r\n\r\n";
+    assert!(dechunk(str::to_bytes(body), None) == Ok(str::to_bytes("hello world")));
+    assert!(dechunk(str::to_bytes(";x\r\nhello\r\n"), None) == Err(~"invalid chunk size"));
+}
+
+#[test]
+fn test_ws_payload() {
+    let frame = WsFrame {
+        fin: true, compressed: false, opcode: WS_TEXT, payload: str::to_bytes("hi")
+    };
+    let wire = encode_ws_frame(&frame, WsClient([9u8, 8u8, 7u8, 6u8]));
+
+    let mut map = HashMap::new();
+    map.insert(str::to_bytes("METHOD"), tnetstring::Str(str::to_bytes("WEBSOCKET")));
+    map.insert(str::to_bytes("FLAGS"), tnetstring::Str(str::to_bytes("0x81")));
+
+    let mut msg = str::to_bytes("abCD-123 56 /ws ");
+    msg.push_all(tnetstring::to_bytes(&tnetstring::Map(map)));
+    msg.push_all(tnetstring::to_bytes(&tnetstring::Str(wire.clone())));
+
+    let request = parse(msg).unwrap();
+    let payload = request.ws_payload().unwrap();
+    assert!(payload == wire);
+
+    let (decoded, _) = decode_ws_frame(payload).unwrap();
+    assert!(decoded == frame);
+
+    assert!(test_request("/ws", [("METHOD", "GET")], []).ws_payload() == None);
+}
+
+#[test]
+fn test_cookie_all() {
+    let request = test_request("/", [("cookie", "x=1; y=3; x=2")], []);
+    assert!(request.cookie_all("x") == ~[~"1", ~"2"]);
+    assert!(request.cookie_all("z").is_empty());
+}
+
+#[test]
+fn test_with_clear_site_data() {
+    let mut headers = Headers();
+    with_clear_site_data(&mut headers, [~"cookies", ~"storage"]).unwrap();
+    assert!(headers.find(&~"Clear-Site-Data") == Some(&~[~"\"cookies\", \"storage\""]));
+
+    let mut headers = Headers();
+    assert!(with_clear_site_data(&mut headers, [~"cookies", ~"a\", \"b"]).is_err());
+    assert!(with_clear_site_data(&mut headers, [~"cache\r\nX-A: 1"]).is_err());
+    assert!(with_clear_site_data(&mut headers, [~""]).is_err());
+    assert!(headers.find(&~"Clear-Site-Data").is_none());
+}
+
+#[test]
+fn test_do_not_track() {
+    assert!(test_request("/", [("dnt", "1")], []).do_not_track() == Some(true));
+    assert!(test_request("/", [("DNT", "0")], []).do_not_track() == Some(false));
+    assert!(test_request("/", [("dnt", "maybe")], []).do_not_track() == None);
+    assert!(test_request("/", [], []).do_not_track() == None);
+}
+
+#[test]
+fn test_with_cookies() {
+    let mut headers = Headers();
+    with_cookies(&mut headers, [
+        Cookie("session", "abc").path("/").http_only().secure(),
+        Cookie("theme", "dark").max_age(86400u).same_site("Lax"),
+    ]).unwrap();
+    with_cookies(&mut headers, [Cookie("lang", "en")]).unwrap();
+
+    let head = str::from_bytes(http_head("HTTP/1.1", 200u, "OK", &headers, None,
+                                         PreserveCase, false));
+    assert!(head.contains("Set-Cookie: session=abc; Path=/; Secure; HttpOnly\r\n"));
+    assert!(head.contains("Set-Cookie: theme=dark; Max-Age=86400; SameSite=Lax\r\n"));
+    assert!(head.contains("Set-Cookie: lang=en\r\n"));
+}
+
+#[test]
+fn test_with_cookies_rejects_injection() {
+    let bad = [
+        Cookie("session", "abc; Domain=evil.example"),
+        Cookie("session", "abc\r\nX-Injected: 1"),
+        Cookie("a=b", "c"),
+        Cookie("", "c"),
+        Cookie("session", "abc").path("/; HttpOnly"),
+        Cookie("session", "abc").domain("example.com,evil.example"),
+        Cookie("session", "abc").same_site("Lax; Secure"),
+    ];
+
+    for cookie in bad.iter() {
+        assert!(cookie.to_header_value().is_err());
+    }
+
+    let mut headers = Headers();
+    assert!(with_cookies(&mut headers, [Cookie("ok", "1"), bad[0u].clone()]).is_err());
+    assert!(headers.find(&~"Set-Cookie").is_none());
+}
+
+#[test]
+fn test_append_via() {
+    let mut headers = Headers();
+    headers.insert(~"via", ~[~"1.0 upstream"]);
+    append_via(&mut headers, "edge", (1u, 1u));
+
+    assert!(headers.find(&~"via") == None);
+    assert!(headers.find(&~"Via") == Some(&~[~"1.0 upstream, 1.1 edge"]));
+
+    let mut headers = Headers();
+    append_via(&mut headers, "edge", (2u, 0u));
+    assert!(headers.find(&~"Via") == Some(&~[~"2.0 edge"]));
+}
+
+#[test]
+fn test_parse_without_method() {
+    let request = parse(
+        str::to_bytes("abCD-123 56 @* 2:{},21:{\"type\":\"disconnect\"},")
+    ).unwrap();
+
+    assert!(request.json_body.is_none());
+    assert!(request.body == str::to_bytes("{\"type\":\"disconnect\"}"));
+    assert!(!request.is_disconnect());
+}
+
+#[test]
+fn test_generate_boundary() {
+    let a = generate_boundary();
+    let b = generate_boundary();
+
+    assert!(a != b);
+    assert!(a.len() == 41u);
+    assert!(a.iter().all(|c| c.is_alphanumeric() && c.is_ascii() || c == '-'));
+}
+
+#[test]
+fn test_reply_upgrade_required() {
+    do with_test_connection |connection| {
+        connection.reply_upgrade_required(&test_request("/", [], []), "TLS/1.2").unwrap();
+
+        let replies = sent_replies();
+        assert!(replies[0u].starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(replies[0u].contains("Upgrade: TLS/1.2\r\n"));
+        assert!(replies[0u].contains("Connection: Upgrade\r\n"));
+    }
+}
+
+#[test]
+fn test_reply_upgrade_required_rejects_newlines() {
+    do with_test_connection |connection| {
+        let req = test_request("/", [], []);
+        assert!(connection.reply_upgrade_required(&req, "TLS/1.2\r\nSet-Cookie: a=b").is_err());
+        assert!(connection.reply_upgrade_required(&req, "").is_err());
+        assert!(connection.reply_upgrade_required(&req, "TLS/1.2, HTTP/1.1").is_ok());
+
+        let replies = sent_replies();
+        assert!(replies.len() == 1u);
+        assert!(replies[0u].contains("Upgrade: TLS/1.2, HTTP/1.1\r\n"));
+    }
+}
+
+#[test]
+fn test_range_unit() {
+    let request = test_request("/items", [("range", "items=0-9")], []);
+    assert!(request.range_unit() == Some(~"items"));
+    assert!(test_request("/", [("range", "bytes=0-")], []).range_unit() == Some(~"bytes"));
+    assert!(test_request("/", [("range", "0-9")], []).range_unit() == None);
+
+    do with_test_connection |connection| {
+        connection.reply_range_not_satisfiable(&request, 42u).unwrap();
+        connection.reply_range_not_satisfiable(&test_request("/", [], []), 42u).unwrap();
+
+        let replies = sent_replies();
+        assert!(replies[0u].starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(replies[0u].contains("Content-Range: items */42\r\n"));
+        assert!(replies[1u].contains("Content-Range: bytes */42\r\n"));
+    }
+}
+
+#[test]
+fn test_raw_headers() {
+    let msg = str::to_bytes("abCD-123 56 / 13:{\"foo\":\"bar\"},11:hello world,");
+    let request = parse(msg).unwrap();
+    assert!(request.raw_headers() == Some("13:{\"foo\":\"bar\"},".as_bytes()));
+
+    let mut map = HashMap::new();
+    map.insert(str::to_bytes("METHOD"), tnetstring::Str(str::to_bytes("GET")));
+    let headers = tnetstring::to_bytes(&tnetstring::Map(map));
+
+    let mut msg = str::to_bytes("abCD-123 56 / ");
+    msg.push_all(headers);
+    msg.push_all(str::to_bytes("0:,"));
+    let request = parse(msg).unwrap();
+    assert!(request.raw_headers() == Some(headers.as_slice()));
+
+    assert!(test_request("/", [], []).raw_headers() == None);
+}
+
+#[test]
+fn test_verify_webhook() {
+    let secret = "webhook secret".as_bytes();
+    let body = str::to_bytes("{\"action\":\"opened\"}");
+    let signature = fmt!("sha256=%s", to_hex(hmac(&mut Sha256::new(), secret, body)));
+
+    let request = test_request("/hook", [("x-hub-signature-256", signature.as_slice())], body);
+    assert!(request.verify_webhook(secret, "x-hub-signature-256", WebhookSha256));
+    assert!(!request.verify_webhook("wrong".as_bytes(), "x-hub-signature-256", WebhookSha256));
+    assert!(!request.verify_webhook(secret, "x-hub-signature-256", WebhookSha1));
+    assert!(!request.verify_webhook(secret, "x-signature", WebhookSha256));
+
+    let signature = to_hex(hmac(&mut Sha1::new(), secret, body));
+    let request = test_request("/hook", [("x-signature", signature.as_slice())], body);
+    assert!(request.verify_webhook(secret, "x-signature", WebhookSha1));
+
+    let request = test_request("/hook", [("x-signature", signature.as_slice())],
+                               str::to_bytes("{\"action\":\"closed\"}"));
+    assert!(!request.verify_webhook(secret, "x-signature", WebhookSha1));
+}
+
+#[test]
+fn test_sorted_headers() {
+    do with_test_connection |connection| {
+        connection.set_sorted_headers(true);
+
+        let mut headers = Headers();
+        set_header(&mut headers, "X-Zebra", ~"3");
+        set_header(&mut headers, "accept-ranges", ~"bytes");
+        set_header(&mut headers, "Cache-Control", ~"no-store");
+        connection.reply_http(&test_request("/", [], []), 200u, "OK", headers, ~"").unwrap();
+
+        assert!(sent_replies() == ~[~"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\
+            accept-ranges: bytes\r\nCache-Control: no-store\r\nX-Zebra: 3\r\n\r\n"]);
+    }
+}
+
+#[test]
+fn test_headers_with_prefix() {
+    let request = test_request("/", [
+        ("x-feature-beta", "on"),
+        ("X-Feature-Alpha", "1"),
+        ("x-feature-beta", "off"),
+        ("x-other", "no"),
+    ], []);
+
+    assert!(request.headers_with_prefix("x-feature-") == ~[
+        (~"X-Feature-Alpha", ~[~"1"]),
+        (~"x-feature-beta", ~[~"on", ~"off"]),
+    ]);
+    assert!(request.headers_with_prefix("x-missing-").is_empty());
+}
+
+#[test]
+fn test_req_fd() {
+    do with_test_connection |connection| {
+        assert!(connection.req_fd().unwrap() >= 0);
+    }
+}
+
+#[test]
+fn test_wait_readable() {
+    do with_test_connection |connection| {
+        let start = time::precise_time_ns();
+        assert!(!connection.wait_readable(20));
+        assert!(time::precise_time_ns() - start < 1000000000u64);
+    }
+
+    do with_pushed_connection("inproc://test-wait-readable") |connection, push| {
+        push.send(str::to_bytes("abCD-123 56 / 2:{},0:,"), 0).unwrap();
+        assert!(connection.wait_readable(-1));
+        assert!(connection.recv_nonblock().unwrap().is_some());
+    }
+}
+
+#[test]
+fn test_sse_retry() {
+    let request = test_request("/events", [("last-event-id", "42")], []);
+    assert!(request.last_event_id() == Some(~"42"));
+    assert!(test_request("/events", [], []).last_event_id() == None);
+
+    do with_test_connection |connection| {
+        let stream = connection.reply_sse_start(&request, Headers(), false).unwrap();
+        connection.reply_sse_retry(&request, &stream, 3000u).unwrap();
+        connection.reply_sse_event(&request, &stream, Some("43"), "a\nb").unwrap();
+        assert!(connection.reply_sse_event(&request, &stream, Some("4\n4"), "c").is_err());
+        assert!(connection.reply_sse_event(&request, &stream, None, "c\rd").is_err());
+
+        let replies = sent_replies();
+        assert!(replies.len() == 3u);
+        assert!(replies[0u].contains("Content-Type: text/event-stream\r\n"));
+        assert!(!replies[0u].contains("Content-Encoding"));
+        assert!(replies[1u] == ~"d\r\nretry: 3000\n\n\r\n");
+        assert!(replies[2u] == ~"18\r\nid: 43\ndata: a\ndata: b\n\n\r\n");
+    }
+}
+
+#[test]
+fn test_sse_gzip() {
+    do with_test_connection |connection| {
+        let request = test_request("/events", [("accept-encoding", "gzip")], []);
+        let stream = connection.reply_sse_start(&request, Headers(), true).unwrap();
+        connection.reply_sse_event(&request, &stream, None, "hi").unwrap();
+
+        let replies = do sent_messages().map |msg| {
+            let (_, _, reply) = split_sent(*msg);
+            reply
+        };
+
+        let head = str::from_bytes(replies[0u]);
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(head.contains("Vary: Accept-Encoding\r\n"));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+
+        // One chunk holding a whole gzip member.
+        let chunk = replies[1u].clone();
+        let start = find_bytes(chunk, "\r\n".as_bytes()).unwrap() + 2u;
+        let member = chunk.slice(start, chunk.len() - 2u);
+        assert!(bytes_start_with(member, [0x1fu8, 0x8bu8]));
+        assert!(inflate::inflate(member.slice(10u, member.len() - 8u), 100u) ==
+            Ok(str::to_bytes("data: hi\n\n")));
+    }
+}
+
+#[test]
+fn test_strip_prefix() {
+    let request = test_request("/api/users?page=2", [], []);
+    assert!(request.strip_prefix("/api") == Some(~"/users"));
+    assert!(request.strip_prefix("/api/") == Some(~"/users"));
+    assert!(request.strip_prefix("/api/users") == Some(~"/"));
+    assert!(request.strip_prefix("/") == Some(~"/api/users"));
+    assert!(request.strip_prefix("/ap") == None);
+    assert!(request.strip_prefix("/admin") == None);
+}
+
+#[cfg(test)]
+fn failing_handler(req: Request) -> Response {
+    if req.path == ~"/boom" { fail!("handler failed"); }
+    response::ok().text("fine").build()
+}
+
+#[test]
+fn test_serve_traps_failures() {
     let ctx = zmq::init(1).unwrap();
+    let push = ctx.socket(zmq::PUSH).unwrap();
+    push.bind("inproc://test-serve").unwrap();
 
     let mut connection = connect(ctx,
         Some(~"F0D32575-2ABB-4957-BC8B-12DAC8AFF13A"),
-        ~[~"tcp://127.0.0.1:9998"],
+        ~[~"inproc://test-serve"],
         ~[~"tcp://127.0.0.1:9999"]);
 
+    let msgs = [
+        "abCD-123 56 /boom 2:{},0:,",
+        "abCD-123 56 @* 17:{\"METHOD\":\"JSON\"},21:{\"type\":\"disconnect\"},",
+        "abCD-123 56 @* 17:{\"METHOD\":\"JSON\"},34:{\"type\":\"credits\",\"credits\":16384},",
+        "abCD-123 57 / 29:{\"content-length\":[\"5\",\"10\"]},5:hello,",
+        "abCD-123 56 /ok 2:{},0:,",
+    ];
+    for msg in msgs.iter() {
+        push.send(msg.as_bytes(), 0).unwrap();
+    }
+
+    // serve only stops on a socket error, so once the last request has
+    // been answered the context is terminated from a thread of its own.
+    let (terminated, terminated_chan) = comm::stream();
+    let terminated_chan = comm::SharedChan::new(terminated_chan);
+    let answered = @mut 0u;
+    connection.on_request_end(|_| {
+        *answered += 1u;
+        if *answered == 2u {
+            let chan = terminated_chan.clone();
+            do task::spawn_sched(task::SingleThreaded) {
+                ctx.term();
+                chan.send(());
+            }
+        }
+    });
+
+    assert!(connection.serve(failing_handler).is_err());
+
+    let sent = sent_messages();
+    assert!(sent.len() == 3u);
+
+    let (_, id, body) = split_sent(sent[0u]);
+    assert!(id == ~"56");
+    assert!(str::from_bytes(body).starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+
+    let (_, id, body) = split_sent(sent[1u]);
+    assert!(id == ~"57");
+    assert!(str::from_bytes(body).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    let (_, id, body) = split_sent(sent[2u]);
+    assert!(id == ~"56");
+    let body = str::from_bytes(body);
+    assert!(body.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(body.ends_with("fine"));
+
     connection.term();
-    ctx.term();
+    push.close();
+    terminated.recv();
 }
 
 #[test]
-fn test_request_parse() {
-    let request = parse(
-        str::to_bytes("abCD-123 56 / 13:{\"foo\":\"bar\"},11:hello world,")
-    ).unwrap();
+fn test_find_bytes() {
+    assert!(find_bytes("aaab".as_bytes(), "aab".as_bytes()) == Some(1u));
+    assert!(find_bytes("abababc".as_bytes(), "ababc".as_bytes()) == Some(2u));
+    assert!(find_bytes("--a--b".as_bytes(), "--b".as_bytes()) == Some(3u));
+    assert!(find_bytes("ababab".as_bytes(), "abc".as_bytes()) == None);
+    assert!(find_bytes("ab".as_bytes(), "abc".as_bytes()) == None);
+}
 
-    assert!(request.uuid == ~"abCD-123");
-    assert!(request.id == ~"56");
-    assert!(request.headers.len() == 1u);
-    let value = match request.headers.find(&~"foo") {
-        Some(header_list) => header_list[0u].clone(),
-        None => ~"",
+#[test]
+fn test_ws_reassembler_limit() {
+    let mut reassembler = WsReassembler();
+    reassembler.set_max_message_size(8u);
+
+    let frame = |fin: bool, opcode: u8, payload: &str| WsFrame {
+        fin: fin, compressed: false, opcode: opcode, payload: str::to_bytes(payload)
     };
-    assert!(value == ~"bar");
-    assert!(request.body == str::to_bytes("hello world"));
+
+    assert!(reassembler.push(frame(false, WS_TEXT, "abcd")).unwrap().is_none());
+    assert!(reassembler.push(frame(false, WS_CONTINUATION, "efgh")).unwrap().is_none());
+    match reassembler.push(frame(false, WS_CONTINUATION, "i")) {
+        Ok(_) => fail!(),
+        Err(e) => assert!(e.code == WS_CLOSE_MESSAGE_TOO_BIG),
+    }
+
+    // The oversized message is dropped, so a new one can start.
+    assert!(reassembler.push(frame(true, WS_TEXT, "ok")).unwrap() ==
+        Some(WsMessage { opcode: WS_TEXT, payload: str::to_bytes("ok") }));
+    assert!(reassembler.push(frame(false, WS_TEXT, "123456789")).is_err());
 }